@@ -0,0 +1,100 @@
+use color_eyre::{
+    Section as _,
+    eyre::{self, Context as _, eyre},
+};
+use librespot_core::{authentication::Credentials, cache::Cache};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// The subdirectory of the OAuth cache, in which named accounts are stored.
+const ACCOUNTS_DIR: &str = "accounts";
+
+/// Gives access to the credentials stored by `spotifyd authenticate`.
+///
+/// The unnamed default account lives directly in the OAuth cache directory, while
+/// every named account (`spotifyd authenticate --account <label>`) gets its own
+/// subdirectory below `accounts/`.
+#[derive(Clone, Debug)]
+pub(crate) struct Accounts {
+    oauth_dir: PathBuf,
+}
+
+impl Accounts {
+    pub(crate) fn new(oauth_dir: PathBuf) -> Self {
+        Self { oauth_dir }
+    }
+
+    pub(crate) fn credentials_dir(&self, account: Option<&str>) -> PathBuf {
+        match account {
+            Some(label) => self.oauth_dir.join(ACCOUNTS_DIR).join(label),
+            None => self.oauth_dir.clone(),
+        }
+    }
+
+    /// Returns the cache used to store the credentials of the given account.
+    pub(crate) fn cache(&self, account: Option<&str>) -> eyre::Result<Cache> {
+        if let Some(label) = account {
+            validate_label(label)?;
+        }
+        Cache::new(Some(self.credentials_dir(account)), None, None, None)
+            .wrap_err("Failed to initialize OAuth cache")
+    }
+
+    /// Loads the stored credentials of the given account, if there are any.
+    pub(crate) fn credentials(&self, account: Option<&str>) -> Option<Credentials> {
+        self.cache(account).ok()?.credentials()
+    }
+
+    /// Lists the labels of all named accounts with stored credentials.
+    pub(crate) fn list(&self) -> Vec<String> {
+        let Ok(entries) = fs::read_dir(self.oauth_dir.join(ACCOUNTS_DIR)) else {
+            return Vec::new();
+        };
+        let mut labels: Vec<String> = entries
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let label = entry.file_name().into_string().ok()?;
+                has_credentials(&entry.path()).then_some(label)
+            })
+            .collect();
+        labels.sort();
+        labels
+    }
+}
+
+fn has_credentials(dir: &Path) -> bool {
+    dir.join("credentials.json").is_file()
+}
+
+/// Makes sure that an account label can safely be used as a directory name.
+pub(crate) fn validate_label(label: &str) -> eyre::Result<()> {
+    if label.is_empty()
+        || !label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(eyre!("invalid account label '{label}'")
+            .with_suggestion(|| "use only letters, digits, '-' and '_'"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_label_validation() {
+        for valid in ["alice", "Bob_2", "living-room"] {
+            assert!(validate_label(valid).is_ok(), "'{valid}' should be valid");
+        }
+        for invalid in ["", "../alice", "a/b", "with space", "."] {
+            assert!(
+                validate_label(invalid).is_err(),
+                "'{invalid}' should be invalid"
+            );
+        }
+    }
+}
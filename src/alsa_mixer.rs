@@ -1,11 +1,29 @@
 use alsa::device_name::HintIter;
+use alsa::mixer::{MilliBel, Selem};
+use alsa::{PollDescriptors, Round};
 use color_eyre::{
     Section,
     eyre::{self, Context, eyre},
 };
-use librespot_playback::mixer::{Mixer, MixerConfig};
-use log::error;
-use std::sync::{Arc, Mutex, MutexGuard};
+use librespot_playback::{
+    config::VolumeCtrl,
+    mixer::{Mixer, MixerConfig, mappings::MappedCtrl},
+};
+use log::{debug, error};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard, Weak},
+    thread,
+};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// How many of the volumes set by spotifyd are remembered, to tell them apart from changes made
+/// by others.
+const MAX_APPLIED: usize = 32;
+/// How often to check whether the mixer is still in use, while there are no events.
+const POLL_TIMEOUT_MS: i32 = 1000;
+/// The gain of a muted control (`SND_CTL_TLV_DB_GAIN_MUTE`).
+const DB_GAIN_MUTE: f64 = -99999.99;
 
 pub fn get_available_controls() -> alsa::Result<HintIter> {
     alsa::device_name::HintIter::new_str(None, "ctl")
@@ -14,6 +32,111 @@ pub fn get_available_controls() -> alsa::Result<HintIter> {
 pub struct AlsaMixer {
     pub mixer: Arc<Mutex<alsa::Mixer>>,
     pub config: MixerConfig,
+    pub scale: VolumeScale,
+    /// The raw volumes that have been set, but not yet been seen by the watcher.
+    pub applied: Arc<Mutex<VecDeque<i64>>>,
+}
+
+/// How volumes are mapped to the raw volumes of the mixer control.
+#[derive(Clone, Debug, Default)]
+pub struct VolumeScale {
+    /// The mapping over the raw volumes of `alsa_linear` (linear) or `alsa` (anything else),
+    /// unless there is a curve.
+    pub volume_ctrl: VolumeCtrl,
+    /// The volume curve, which is applied to the dB range of the control.
+    pub curve: Option<VolumeCtrl>,
+    /// The volume in dB just above zero.
+    pub min_db: Option<f64>,
+    /// The volume in dB at full volume.
+    pub max_db: Option<f64>,
+}
+
+impl VolumeScale {
+    /// The raw volumes above zero, limited to `min_db` and `max_db`.
+    fn raw_range(&self, elem: &Selem) -> (i64, i64) {
+        let (mut min, mut max) = elem.get_playback_volume_range();
+        if let Some(db) = self.max_db
+            && let Ok(raw) = elem.ask_playback_db_vol(MilliBel::from_db(db as f32), Round::Floor)
+        {
+            max = raw.clamp(min, max);
+        }
+        if let Some(db) = self.min_db
+            && let Ok(raw) = elem.ask_playback_db_vol(MilliBel::from_db(db as f32), Round::Ceil)
+        {
+            min = raw.clamp(min, max);
+        }
+        (min, max)
+    }
+
+    fn raw_volume(&self, volume: u16, elem: &Selem) -> i64 {
+        if volume == 0 {
+            return elem.get_playback_volume_range().0;
+        }
+        let range = self.raw_range(elem);
+        let Some(curve) = self.curve else {
+            return to_raw(volume, range, self.volume_ctrl);
+        };
+        let mapped = curve.to_mapped(volume);
+        match db_range(elem, range) {
+            Some((min_db, max_db)) => {
+                let db = (max_db + 20.0 * mapped.log10()).max(min_db);
+                elem.ask_playback_db_vol(MilliBel::from_db(db as f32), Round::Floor)
+                    .map_or(range.0, |raw| raw.clamp(range.0, range.1))
+            }
+            None => range.0 + (mapped * (range.1 - range.0) as f64).round() as i64,
+        }
+    }
+
+    fn volume(&self, raw: i64, elem: &Selem) -> u16 {
+        if raw <= elem.get_playback_volume_range().0 {
+            return 0;
+        }
+        let range = self.raw_range(elem);
+        let raw = raw.clamp(range.0, range.1);
+        let volume = match self.curve {
+            None => from_raw(raw, range, self.volume_ctrl),
+            Some(curve) => {
+                let mapped = match db_range(elem, range) {
+                    Some((_, max_db)) => elem
+                        .ask_playback_vol_db(raw)
+                        .map_or(0.0, |db| 10f64.powf((db.to_db() as f64 - max_db) / 20.0)),
+                    None => (raw - range.0) as f64 / (range.1 - range.0).max(1) as f64,
+                };
+                curve.as_unmapped(mapped.clamp(0.0, 1.0))
+            }
+        };
+        // only the lowest raw volume is zero
+        volume.max(1)
+    }
+}
+
+/// The dB range of the raw volumes, if the control has one.
+fn db_range(elem: &Selem, (min, max): (i64, i64)) -> Option<(f64, f64)> {
+    let mut min_db = elem.ask_playback_vol_db(min).ok()?.to_db() as f64;
+    if min_db <= DB_GAIN_MUTE {
+        // the lowest raw volume is often muted, the next one isn't
+        min_db = elem.ask_playback_vol_db((min + 1).min(max)).ok()?.to_db() as f64;
+    }
+    let max_db = elem.ask_playback_vol_db(max).ok()?.to_db() as f64;
+    (max_db > min_db).then_some((min_db, max_db))
+}
+
+fn to_raw(volume: u16, (min, max): (i64, i64), volume_ctrl: VolumeCtrl) -> i64 {
+    let volume_steps = (max - min) as f64;
+    if matches!(volume_ctrl, VolumeCtrl::Linear) {
+        (((volume as f64) / (u16::MAX as f64)) * volume_steps) as i64 + min
+    } else {
+        ((volume as f64 + 1.0).log((u16::MAX as f64) + 1.0) * volume_steps).floor() as i64 + min
+    }
+}
+
+fn from_raw(raw: i64, (min, max): (i64, i64), volume_ctrl: VolumeCtrl) -> u16 {
+    let volume_steps = (max - min) as f64;
+    if matches!(volume_ctrl, VolumeCtrl::Linear) {
+        ((raw - min) as f64 * u16::MAX as f64 / volume_steps).floor() as u16
+    } else {
+        ((u16::MAX as f64 + 1.0).powf(((raw - min) as f64) / volume_steps) - 1.0).floor() as u16
+    }
 }
 
 impl AlsaMixer {
@@ -53,18 +176,20 @@ impl AlsaMixer {
     fn set_volume_with_err(&self, volume: u16) -> eyre::Result<()> {
         let lock = self.mixer.lock().expect("lock shouldn't be poisoned");
         let elem = self.get_selem(&lock)?;
-        let (min, max) = elem.get_playback_volume_range();
-
-        let volume_steps = (max - min) as f64;
-        let normalised_volume = if matches!(
-            self.config.volume_ctrl,
-            librespot_playback::config::VolumeCtrl::Linear
-        ) {
-            (((volume as f64) / (u16::MAX as f64)) * volume_steps) as i64 + min
-        } else {
-            ((volume as f64 + 1.0).log((u16::MAX as f64) + 1.0) * volume_steps).floor() as i64 + min
-        };
+        let current = elem.get_playback_volume(alsa::mixer::SelemChannelId::mono())?;
+        // e.g. the volume reported by the watcher, which would only be rounded
+        if self.scale.volume(current, &elem) == volume {
+            return Ok(());
+        }
 
+        let normalised_volume = self.scale.raw_volume(volume, &elem);
+        if normalised_volume != current {
+            let mut applied = self.applied.lock().expect("lock shouldn't be poisoned");
+            if applied.len() == MAX_APPLIED {
+                applied.pop_front();
+            }
+            applied.push_back(normalised_volume);
+        }
         elem.set_playback_volume_all(normalised_volume)?;
         Ok(())
     }
@@ -72,18 +197,82 @@ impl AlsaMixer {
     fn get_volume_with_err(&self) -> eyre::Result<u16> {
         let lock = self.mixer.lock().expect("lock shouldn't be poisoned");
         let elem = self.get_selem(&lock)?;
-        let (min, max) = elem.get_playback_volume_range();
-        let volume_steps = (max - min) as f64;
         let vol = elem.get_playback_volume(alsa::mixer::SelemChannelId::mono())?;
-        let normalized_volume = if matches!(
-            self.config.volume_ctrl,
-            librespot_playback::config::VolumeCtrl::Linear
-        ) {
-            ((vol - min) as f64 * u16::MAX as f64 / volume_steps).floor() as u16
-        } else {
-            ((u16::MAX as f64 + 1.0).powf(((vol - min) as f64) / volume_steps) - 1.0).floor() as u16
+        Ok(self.scale.volume(vol, &elem))
+    }
+
+    /// Whether the control can be muted with its playback switch.
+    pub(crate) fn has_playback_switch(&self) -> bool {
+        let lock = self.mixer.lock().expect("lock shouldn't be poisoned");
+        self.get_selem(&lock)
+            .is_ok_and(|elem| elem.has_playback_switch())
+    }
+
+    /// Mutes or unmutes the control with its playback switch.
+    pub(crate) fn set_muted(&self, muted: bool) {
+        let lock = self.mixer.lock().expect("lock shouldn't be poisoned");
+        let result = self
+            .get_selem(&lock)
+            .and_then(|elem| Ok(elem.set_playback_switch_all(if muted { 0 } else { 1 })?));
+        if let Err(err) = result {
+            error!("Couldn't switch playback of alsa device: {err:?}");
+        }
+    }
+
+    /// Reports changes of the volume made by others, e.g. with `amixer` or a knob on the device,
+    /// until the mixer is dropped.
+    pub(crate) fn watch(&self, changes: UnboundedSender<u16>) -> eyre::Result<()> {
+        let mixer =
+            alsa::Mixer::new(&self.config.device, false).wrap_err("failed to open mixer")?;
+        let config = self.config.clone();
+        let scale = self.scale.clone();
+        let applied = Arc::downgrade(&self.applied);
+        thread::spawn(move || {
+            if let Err(err) = watch_events(&mixer, &config, &scale, applied, &changes) {
+                error!("Stopped watching the volume of the alsa device: {err:?}");
+            }
+        });
+        Ok(())
+    }
+}
+
+fn watch_events(
+    mixer: &alsa::Mixer,
+    config: &MixerConfig,
+    scale: &VolumeScale,
+    applied: Weak<Mutex<VecDeque<i64>>>,
+    changes: &UnboundedSender<u16>,
+) -> eyre::Result<()> {
+    let selem_id = alsa::mixer::SelemId::new(&config.control, config.index);
+    let read = || {
+        let selem = mixer.find_selem(&selem_id)?;
+        let raw = selem
+            .get_playback_volume(alsa::mixer::SelemChannelId::mono())
+            .ok()?;
+        Some((raw, scale.volume(raw, &selem)))
+    };
+    let mut known = read().map(|(raw, _)| raw);
+    let mut fds = mixer.get()?;
+    loop {
+        alsa::poll::poll(&mut fds, POLL_TIMEOUT_MS)?;
+        mixer.handle_events()?;
+        let Some(applied) = applied.upgrade() else {
+            return Ok(());
         };
-        Ok(normalized_volume)
+        let Some((raw, volume)) = read().filter(|(raw, _)| known != Some(*raw)) else {
+            continue;
+        };
+        known = Some(raw);
+        let mut applied = applied.lock().expect("lock shouldn't be poisoned");
+        // our own changes are reported, too
+        if let Some(pos) = applied.iter().position(|applied| *applied == raw) {
+            applied.drain(..=pos);
+            continue;
+        }
+        debug!("The volume of the alsa device has been changed to {volume}");
+        if changes.send(volume).is_err() {
+            return Ok(());
+        }
     }
 }
 
@@ -94,7 +283,12 @@ impl Mixer for AlsaMixer {
             .map_err(librespot_core::Error::invalid_argument)?;
         Ok(AlsaMixer {
             mixer: Arc::new(Mutex::new(mixer)),
+            scale: VolumeScale {
+                volume_ctrl: config.volume_ctrl,
+                ..VolumeScale::default()
+            },
             config,
+            applied: Arc::default(),
         })
     }
 
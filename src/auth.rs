@@ -0,0 +1,479 @@
+use crate::{
+    LogTarget,
+    accounts::{self, Accounts},
+    config::{AuthAction, CliConfig, CredentialsSelector, ZEROCONF_CREDENTIALS_DIR},
+    setup_logger,
+};
+use color_eyre::{
+    Section as _,
+    eyre::{self, Context as _, bail, eyre},
+};
+use librespot_core::{Session, SessionConfig, authentication::Credentials};
+use log::{info, warn};
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Read as _, Write as _},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+use tokio::runtime::Runtime;
+use url::Url;
+
+const CREDENTIALS_FILE: &str = "credentials.json";
+
+/// One of the places where `spotifyd` stores credentials.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum CredentialsLocation {
+    /// An account stored with `spotifyd authenticate`, the default one if unnamed.
+    OAuth(Option<String>),
+    /// The credentials remembered from the last discovery login.
+    Zeroconf,
+}
+
+impl CredentialsLocation {
+    fn from_selector(selector: CredentialsSelector) -> eyre::Result<Self> {
+        if selector.zeroconf {
+            return Ok(Self::Zeroconf);
+        }
+        if let Some(label) = selector.account.as_deref() {
+            accounts::validate_label(label)?;
+        }
+        Ok(Self::OAuth(selector.account))
+    }
+
+    fn path(&self, cache_dir: &Path, accounts: &Accounts) -> PathBuf {
+        let dir = match self {
+            Self::OAuth(account) => accounts.credentials_dir(account.as_deref()),
+            Self::Zeroconf => cache_dir.join(ZEROCONF_CREDENTIALS_DIR),
+        };
+        dir.join(CREDENTIALS_FILE)
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Self::OAuth(None) => "default account".to_string(),
+            Self::OAuth(Some(label)) => format!("account '{label}'"),
+            Self::Zeroconf => "last discovery login".to_string(),
+        }
+    }
+}
+
+/// Credentials that are provided from outside, e.g. as a container secret.
+///
+/// These are configured with `credentials_file` or `credentials_cmd` and take precedence over
+/// any credentials stored in the cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum CredentialsSource {
+    File(PathBuf),
+    Command(String),
+}
+
+impl fmt::Display for CredentialsSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "credentials_file {path:?}"),
+            Self::Command(cmd) => write!(f, "credentials_cmd {cmd:?}"),
+        }
+    }
+}
+
+impl CredentialsSource {
+    pub(crate) fn load(&self, shell: &str) -> eyre::Result<Credentials> {
+        let content = match self {
+            Self::File(path) => {
+                check_permissions(path)?;
+                fs::read_to_string(path).wrap_err_with(|| format!("failed to read {path:?}"))?
+            }
+            Self::Command(cmd) => run_credentials_cmd(shell, cmd)?,
+        };
+        // don't include the content in the error, it might be (almost) valid credentials
+        parse_credentials(&content).wrap_err_with(|| format!("invalid credentials from {self}"))
+    }
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> eyre::Result<()> {
+    use std::os::unix::fs::MetadataExt as _;
+
+    let metadata = fs::metadata(path).wrap_err_with(|| format!("failed to access {path:?}"))?;
+    if !metadata.is_file() {
+        bail!("{path:?} is not a file");
+    }
+    // SAFETY: geteuid is always successful
+    let euid = unsafe { libc::geteuid() };
+    check_mode(metadata.mode(), metadata.uid(), euid).wrap_err_with(|| {
+        format!("refusing to read credentials from {path:?}, because it is not private")
+    })
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> eyre::Result<()> {
+    Ok(())
+}
+
+/// Makes sure that a credentials file can't be accessed or replaced by other users.
+#[cfg(unix)]
+fn check_mode(mode: u32, owner: u32, euid: u32) -> eyre::Result<()> {
+    if owner != euid && owner != 0 {
+        return Err(eyre!("the file is owned by uid {owner}")
+            .with_suggestion(|| "the file must be owned by the user running spotifyd or root"));
+    }
+    if mode & 0o077 != 0 {
+        return Err(eyre!("the file has mode {:o}", mode & 0o777).with_suggestion(
+            || "remove all permissions of group and others, e.g. with `chmod 600` or `defaultMode: 0400` for Kubernetes secrets",
+        ));
+    }
+    Ok(())
+}
+
+fn run_credentials_cmd(shell: &str, cmd: &str) -> eyre::Result<String> {
+    info!("Running credentials_cmd {cmd:?} using {shell:?}");
+    let output = Command::new(shell)
+        .arg("-c")
+        .arg(cmd)
+        .stdin(Stdio::null())
+        .output()
+        .wrap_err_with(|| format!("failed to run credentials_cmd {cmd:?}"))?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "credentials_cmd {cmd:?} failed with {}",
+            output.status
+        ))
+        .section(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    String::from_utf8(output.stdout).wrap_err("credentials_cmd printed invalid UTF-8")
+}
+
+pub(crate) fn run_auth_command(mut cli_config: CliConfig, action: AuthAction) -> eyre::Result<()> {
+    setup_logger(LogTarget::Terminal, cli_config.verbose)?;
+
+    cli_config
+        .load_config_file_values()
+        .wrap_err("failed to read config file")?;
+
+    let shared_config = &cli_config.shared_config;
+    let cache_dir = shared_config.get_cache_dir()?.into_owned();
+    let accounts = shared_config.get_accounts()?;
+
+    match action {
+        AuthAction::Status { offline } => {
+            let proxy = (!offline).then(|| shared_config.proxy_url());
+            show_status(&cache_dir, &accounts, proxy)
+        }
+        AuthAction::Logout { credentials, all } => {
+            let locations = if all {
+                all_locations(&accounts)
+            } else {
+                vec![CredentialsLocation::from_selector(credentials)?]
+            };
+            for location in locations {
+                logout(&location, &cache_dir, &accounts)?;
+            }
+            Ok(())
+        }
+        AuthAction::Export {
+            credentials,
+            output,
+        } => {
+            let location = CredentialsLocation::from_selector(credentials)?;
+            export(&location, &cache_dir, &accounts, output.as_deref())
+        }
+        AuthAction::Import { credentials, input } => {
+            let location = CredentialsLocation::from_selector(credentials)?;
+            import(&location, &cache_dir, &accounts, input.as_deref())
+        }
+    }
+}
+
+fn all_locations(accounts: &Accounts) -> Vec<CredentialsLocation> {
+    let mut locations = vec![CredentialsLocation::OAuth(None)];
+    locations.extend(
+        accounts
+            .list()
+            .into_iter()
+            .map(|label| CredentialsLocation::OAuth(Some(label))),
+    );
+    locations.push(CredentialsLocation::Zeroconf);
+    locations
+}
+
+fn read_credentials(path: &Path) -> eyre::Result<Option<Credentials>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).wrap_err_with(|| format!("failed to read {path:?}")),
+    };
+    parse_credentials(&content)
+        .map(Some)
+        .wrap_err_with(|| format!("failed to parse {path:?}"))
+}
+
+fn parse_credentials(content: &str) -> eyre::Result<Credentials> {
+    let creds: Credentials = serde_json::from_str(content)?;
+    if creds.auth_data.is_empty() {
+        bail!("the credentials don't contain any authentication data");
+    }
+    Ok(creds)
+}
+
+/// Writes a file that is only readable by the current user.
+///
+/// An existing file is restricted as well, before anything is written to it.
+pub(crate) fn write_private_file(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    // truncated only once the permissions are tightened
+    options.write(true).create(true).truncate(false);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt as _;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt as _;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.set_len(0)?;
+    file.write_all(content)
+}
+
+/// Overwrites the file before removing it, so that the credentials can't be restored easily.
+fn remove_securely(path: &Path) -> io::Result<()> {
+    let len = fs::metadata(path)?.len();
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.write_all(&vec![0; len as usize])?;
+    file.sync_all()?;
+    drop(file);
+    fs::remove_file(path)
+}
+
+async fn check_credentials(creds: Credentials, proxy: Option<Url>) -> eyre::Result<String> {
+    let session = Session::new(
+        SessionConfig {
+            proxy,
+            ..Default::default()
+        },
+        None,
+    );
+    session.connect(creds, false).await?;
+    let username = session.username();
+    session.shutdown();
+    Ok(username)
+}
+
+fn show_status(
+    cache_dir: &Path,
+    accounts: &Accounts,
+    proxy: Option<Option<Url>>,
+) -> eyre::Result<()> {
+    let runtime = proxy.is_some().then(|| Runtime::new().unwrap());
+    let mut found_any = false;
+    for location in all_locations(accounts) {
+        let path = location.path(cache_dir, accounts);
+        let creds = match read_credentials(&path) {
+            Ok(Some(creds)) => creds,
+            Ok(None) => continue,
+            Err(err) => {
+                println!("{}: unreadable ({err:#})", location.describe());
+                found_any = true;
+                continue;
+            }
+        };
+        found_any = true;
+
+        let username = creds
+            .username
+            .clone()
+            .unwrap_or_else(|| "unknown".to_string());
+        let validity = match (&runtime, &proxy) {
+            (Some(runtime), Some(proxy)) => {
+                match runtime.block_on(check_credentials(creds, proxy.clone())) {
+                    Ok(_) => "valid".to_string(),
+                    Err(err) => format!("login failed: {err}"),
+                }
+            }
+            _ => "not checked".to_string(),
+        };
+        println!(
+            "{}: user {username}, {validity}\n    stored in {}",
+            location.describe(),
+            path.display()
+        );
+    }
+
+    if !found_any {
+        println!("No credentials stored in {}", cache_dir.display());
+    }
+    Ok(())
+}
+
+fn logout(
+    location: &CredentialsLocation,
+    cache_dir: &Path,
+    accounts: &Accounts,
+) -> eyre::Result<()> {
+    let path = location.path(cache_dir, accounts);
+    match remove_securely(&path) {
+        Ok(()) => info!("Removed credentials of {}", location.describe()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            info!("No credentials stored for {}", location.describe());
+            return Ok(());
+        }
+        Err(err) => {
+            return Err(err).wrap_err_with(|| format!("failed to remove {path:?}"));
+        }
+    }
+    if let CredentialsLocation::OAuth(Some(_)) = location
+        && let Some(dir) = path.parent()
+    {
+        // only succeeds if the directory is empty, which is fine
+        let _ = fs::remove_dir(dir);
+    }
+    Ok(())
+}
+
+fn export(
+    location: &CredentialsLocation,
+    cache_dir: &Path,
+    accounts: &Accounts,
+    output: Option<&Path>,
+) -> eyre::Result<()> {
+    let path = location.path(cache_dir, accounts);
+    let creds = read_credentials(&path)?.ok_or_else(|| {
+        eyre!("no credentials stored for {}", location.describe())
+            .with_suggestion(|| "check `spotifyd auth status` for the available credentials")
+    })?;
+    let content = serde_json::to_string_pretty(&creds)?;
+
+    match output {
+        Some(output) => {
+            write_private_file(output, content.as_bytes())
+                .wrap_err_with(|| format!("failed to write {output:?}"))?;
+            info!(
+                "Exported credentials of {} to {output:?}",
+                location.describe()
+            );
+        }
+        None => println!("{content}"),
+    }
+    warn!("The exported credentials give full access to your Spotify account, keep them secret!");
+    Ok(())
+}
+
+fn import(
+    location: &CredentialsLocation,
+    cache_dir: &Path,
+    accounts: &Accounts,
+    input: Option<&Path>,
+) -> eyre::Result<()> {
+    let content = match input {
+        Some(input) => {
+            fs::read_to_string(input).wrap_err_with(|| format!("failed to read {input:?}"))?
+        }
+        None => {
+            let mut content = String::new();
+            io::stdin()
+                .read_to_string(&mut content)
+                .wrap_err("failed to read stdin")?;
+            content
+        }
+    };
+    let creds = parse_credentials(&content).wrap_err("invalid credentials")?;
+
+    let path = location.path(cache_dir, accounts);
+    if path.exists() {
+        warn!(
+            "Replacing the existing credentials of {}",
+            location.describe()
+        );
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).wrap_err_with(|| format!("failed to create {dir:?}"))?;
+    }
+    let content = serde_json::to_string(&creds)?;
+    write_private_file(&path, content.as_bytes())
+        .wrap_err_with(|| format!("failed to write {path:?}"))?;
+
+    info!(
+        "Imported credentials of user {} as {}",
+        creds.username.as_deref().unwrap_or("unknown"),
+        location.describe()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_credentials_format() {
+        let exported = r#"{
+            "username": "alice",
+            "auth_type": 1,
+            "auth_data": "c2VjcmV0"
+        }"#;
+        let creds = parse_credentials(exported).expect("documented format should be accepted");
+        assert_eq!(creds.username.as_deref(), Some("alice"));
+        assert_eq!(creds.auth_data, b"secret");
+
+        let roundtrip = serde_json::to_string(&creds).unwrap();
+        assert_eq!(parse_credentials(&roundtrip).unwrap(), creds);
+
+        assert!(
+            parse_credentials(r#"{"username": "alice", "auth_type": 1, "auth_data": ""}"#).is_err()
+        );
+        assert!(parse_credentials("not json").is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_credentials_file_permissions() {
+        assert!(check_mode(0o100600, 1000, 1000).is_ok());
+        assert!(check_mode(0o100400, 0, 1000).is_ok());
+        assert!(check_mode(0o100640, 1000, 1000).is_err());
+        assert!(check_mode(0o100604, 0, 1000).is_err());
+        assert!(check_mode(0o100600, 1001, 1000).is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_private_file_permissions() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("credentials.json");
+        fs::write(&path, "a longer previous content").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_private_file(&path, b"secret").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"secret");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_credentials_cmd() {
+        let source = CredentialsSource::Command(
+            r#"echo '{"username":"alice","auth_type":1,"auth_data":"c2VjcmV0"}'"#.to_string(),
+        );
+        let creds = source
+            .load("sh")
+            .expect("command should provide credentials");
+        assert_eq!(creds.username.as_deref(), Some("alice"));
+
+        assert!(
+            CredentialsSource::Command("exit 1".to_string())
+                .load("sh")
+                .is_err()
+        );
+        assert!(
+            CredentialsSource::Command("echo nope".to_string())
+                .load("sh")
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,248 @@
+//! A mixer that controls the volume of an external amplifier by running commands.
+//!
+//! The set-volume command gets the volume in percent as its last argument, the get-volume
+//! command prints it. Since volume sliders change the volume many times per second, changes
+//! are debounced and the volume that has been read is cached for a while.
+
+use librespot_playback::mixer::{Mixer, MixerConfig};
+use log::{debug, warn};
+use std::{
+    process::{Command, Stdio},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long to wait for further changes, before setting the volume.
+const DEBOUNCE: Duration = Duration::from_millis(150);
+/// How long the volume that has been read is used, before reading it again.
+const CACHE_TTL: Duration = Duration::from_secs(5);
+
+struct State {
+    volume: u16,
+    /// When the volume has been read or set the last time.
+    updated: Option<Instant>,
+    /// A volume that has yet to be set.
+    pending: Option<u16>,
+    refresh: bool,
+    closed: bool,
+}
+
+struct Shared {
+    shell: String,
+    set_cmd: String,
+    get_cmd: Option<String>,
+    /// How long to wait for further changes, before setting the volume.
+    debounce: Duration,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+pub(crate) struct CommandMixer {
+    shared: Arc<Shared>,
+}
+
+fn to_percent(volume: u16) -> u16 {
+    ((volume as u32 * 100 + u16::MAX as u32 / 2) / u16::MAX as u32) as u16
+}
+
+fn from_percent(percent: u16) -> u16 {
+    (percent.min(100) as u32 * u16::MAX as u32 / 100) as u16
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("command mixer has been poisoned")
+    }
+
+    fn run(&self, cmd: &str) -> Option<String> {
+        debug!("Running volume command {cmd:?}");
+        let output = Command::new(&self.shell)
+            .arg("-c")
+            .arg(cmd)
+            .stdin(Stdio::null())
+            .output();
+        match output {
+            Ok(output) if output.status.success() => {
+                Some(String::from_utf8_lossy(&output.stdout).into_owned())
+            }
+            Ok(output) => {
+                warn!(
+                    "Volume command {cmd:?} failed with {}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+                None
+            }
+            Err(err) => {
+                warn!("Failed to run volume command {cmd:?}: {err}");
+                None
+            }
+        }
+    }
+
+    fn read_volume(&self) -> Option<u16> {
+        let output = self.run(self.get_cmd.as_deref()?)?;
+        match output.trim().trim_end_matches('%').parse::<f64>() {
+            Ok(percent) => Some(from_percent(percent.round().clamp(0.0, 100.0) as u16)),
+            Err(_) => {
+                warn!("The get-volume command printed {output:?} instead of a percentage");
+                None
+            }
+        }
+    }
+
+    /// Sets pending volumes and refreshes the cached one, until the mixer is dropped.
+    fn work(&self) {
+        let mut state = self.state();
+        loop {
+            state = self
+                .changed
+                .wait_while(state, |state| {
+                    state.pending.is_none() && !state.refresh && !state.closed
+                })
+                .expect("command mixer has been poisoned");
+            if state.closed {
+                return;
+            }
+            if let Some(mut volume) = state.pending {
+                // wait until the volume stops changing
+                loop {
+                    drop(state);
+                    thread::sleep(self.debounce);
+                    state = self.state();
+                    match state.pending {
+                        Some(pending) if pending != volume => volume = pending,
+                        _ => break,
+                    }
+                }
+                state.pending = None;
+                drop(state);
+                self.run(&format!("{} {}", self.set_cmd, to_percent(volume)));
+                state = self.state();
+                state.updated = Some(Instant::now());
+            }
+            if state.refresh {
+                state.refresh = false;
+                drop(state);
+                let volume = self.read_volume();
+                state = self.state();
+                if let Some(volume) = volume.filter(|_| state.pending.is_none()) {
+                    state.volume = volume;
+                }
+                state.updated = Some(Instant::now());
+            }
+        }
+    }
+}
+
+impl CommandMixer {
+    pub(crate) fn new(shell: String, set_cmd: String, get_cmd: Option<String>) -> Self {
+        Self::with_debounce(shell, set_cmd, get_cmd, DEBOUNCE)
+    }
+
+    fn with_debounce(
+        shell: String,
+        set_cmd: String,
+        get_cmd: Option<String>,
+        debounce: Duration,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            shell,
+            set_cmd,
+            get_cmd,
+            debounce,
+            state: Mutex::new(State {
+                volume: u16::MAX / 2,
+                updated: None,
+                pending: None,
+                refresh: false,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        });
+        if let Some(volume) = shared.read_volume() {
+            let mut state = shared.state();
+            state.volume = volume;
+            state.updated = Some(Instant::now());
+        }
+        let worker = shared.clone();
+        thread::spawn(move || worker.work());
+        Self { shared }
+    }
+}
+
+impl Drop for CommandMixer {
+    fn drop(&mut self) {
+        self.shared.state().closed = true;
+        self.shared.changed.notify_one();
+    }
+}
+
+impl Mixer for CommandMixer {
+    fn open(_: MixerConfig) -> Result<Self, librespot_core::Error> {
+        Err(librespot_core::Error::unimplemented(
+            "the command mixer needs the volume commands",
+        ))
+    }
+
+    fn volume(&self) -> u16 {
+        let mut state = self.shared.state();
+        let stale = state
+            .updated
+            .is_none_or(|updated| updated.elapsed() > CACHE_TTL);
+        if stale && self.shared.get_cmd.is_some() && state.pending.is_none() {
+            state.refresh = true;
+            self.shared.changed.notify_one();
+        }
+        state.volume
+    }
+
+    fn set_volume(&self, volume: u16) {
+        let mut state = self.shared.state();
+        state.volume = volume;
+        state.pending = Some(volume);
+        self.shared.changed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_command_mixer() {
+        let log = tempfile::NamedTempFile::new().unwrap();
+        let log = log.path();
+        std::fs::write(log, "40\n").unwrap();
+        // long enough that the changes below are coalesced, even on a slow machine
+        let mixer = CommandMixer::with_debounce(
+            "sh".to_string(),
+            format!("echo >> {}", log.display()),
+            Some(format!("tail -n 1 {}", log.display())),
+            Duration::from_millis(500),
+        );
+        assert_eq!(to_percent(mixer.volume()), 40);
+
+        // dragging a volume slider doesn't set every volume
+        for percent in 41..=60 {
+            mixer.set_volume(from_percent(percent));
+        }
+        assert_eq!(to_percent(mixer.volume()), 60);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let written = loop {
+            let written = std::fs::read_to_string(log).unwrap();
+            if written.ends_with("\n60\n") || Instant::now() > deadline {
+                break written;
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+        let written: Vec<&str> = written.lines().collect();
+        assert_eq!(written.last(), Some(&"60"));
+        assert!(
+            written.len() - 1 < 20,
+            "every change has been written: {written:?}"
+        );
+    }
+}
@@ -1,4 +1,7 @@
-use crate::utils;
+use crate::{
+    accounts::Accounts, auth::CredentialsSource, crossfade::MAX_CROSSFADE_SECONDS, network_sink,
+    utils,
+};
 use clap::{
     Args, Parser, Subcommand, ValueEnum,
     builder::{IntoResettable, PossibleValuesParser, TypedValueParser, ValueParser},
@@ -12,7 +15,7 @@ use gethostname::gethostname;
 use librespot_core::{cache::Cache, config::DeviceType as LSDeviceType, config::SessionConfig};
 use librespot_playback::{
     audio_backend,
-    config::{AudioFormat as LSAudioFormat, Bitrate as LSBitrate, PlayerConfig},
+    config::{AudioFormat as LSAudioFormat, Bitrate as LSBitrate, PlayerConfig, VolumeCtrl},
     dither::{DithererBuilder, TriangularDitherer, mk_ditherer},
 };
 use log::{debug, error, info, warn};
@@ -23,14 +26,19 @@ use serde::{
 use sha1::{Digest, Sha1};
 use std::{
     borrow::Cow,
+    collections::BTreeMap,
     convert::TryInto,
-    fs,
+    fmt, fs,
+    net::IpAddr,
     path::{Path, PathBuf},
     str::FromStr,
+    time::Duration,
 };
 use url::Url;
 
 const CONFIG_FILE_NAME: &str = "spotifyd.conf";
+/// The subdirectory of the cache, in which the credentials of the last discovery login are stored.
+pub(crate) const ZEROCONF_CREDENTIALS_DIR: &str = "zeroconf";
 
 #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, ValueEnum)]
 #[serde(rename_all = "snake_case")]
@@ -39,11 +47,35 @@ pub enum VolumeController {
     Alsa,
     #[cfg(feature = "alsa_backend")]
     AlsaLinear,
+    #[cfg(feature = "pulseaudio_backend")]
+    Pulseaudio,
     #[serde(rename = "softvol")]
     SoftVolume,
+    Command,
     None,
 }
 
+/// How the volume in Spotify maps to the loudness.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum VolumeCurve {
+    Linear,
+    /// Logarithmic over `volume_range_db`
+    Log,
+    /// Cubic over `volume_range_db`
+    Cubic,
+}
+
+/// Whose volume is remembered and restored when they take over.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum RememberVolume {
+    /// Per Spotify user
+    User,
+    /// Per Spotify user and client, e.g. their phone
+    Client,
+}
+
 // Spotify's device type (copied from it's config.rs)
 #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, ValueEnum)]
 #[serde(rename_all = "snake_case")]
@@ -139,6 +171,41 @@ impl From<Bitrate> for LSBitrate {
     }
 }
 
+/// When a discovery login may replace an active session.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum TakeoverPolicy {
+    /// Always accept the new login
+    #[default]
+    Always,
+    /// Only if nothing has been playing for `takeover_idle_minutes`
+    Idle,
+    /// Only if the same user logs in again
+    SameUser,
+    /// Only if nothing is playing right now
+    NotWhilePlaying,
+}
+
+/// The IP versions used to advertise the device via zeroconf.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum IpFamily {
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+impl IpFamily {
+    pub(crate) fn contains(self, addr: &IpAddr) -> bool {
+        match self {
+            IpFamily::Any => true,
+            IpFamily::Ipv4 => addr.is_ipv4(),
+            IpFamily::Ipv6 => addr.is_ipv6(),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, ValueEnum)]
 #[serde(rename_all = "snake_case")]
 pub enum DBusType {
@@ -168,7 +235,11 @@ impl From<AudioFormat> for LSAudioFormat {
 }
 
 fn possible_backends() -> Vec<&'static str> {
-    audio_backend::BACKENDS.iter().map(|b| b.0).collect()
+    audio_backend::BACKENDS
+        .iter()
+        .map(|b| b.0)
+        .chain(network_sink::BACKENDS)
+        .collect()
 }
 
 fn deserialize_backend<'de, D>(de: D) -> Result<Option<String>, D::Error>
@@ -240,18 +311,127 @@ pub struct CliConfig {
     #[command(subcommand)]
     pub mode: Option<ExecutionMode>,
 
+    /// The devices defined by `[[device]]` tables in the config file
+    #[arg(skip)]
+    pub devices: Vec<DeviceConfig>,
+
     #[command(flatten)]
     pub shared_config: SharedConfigValues,
 }
 
 #[derive(Debug, Subcommand)]
 pub enum ExecutionMode {
-    #[command(visible_alias = "auth")]
-    Authenticate {
-        /// The port to use for the OAuth redirect
-        #[arg(long, default_value_t = 8000)]
-        oauth_port: u16,
+    #[command(visible_alias = "auth", args_conflicts_with_subcommands = true)]
+    Authenticate(AuthenticateArgs),
+    /// Connect a Snapcast stream to a running spotifyd (use as `controlscript` in snapserver)
+    #[cfg(unix)]
+    SnapcastControl(SnapcastControlArgs),
+    /// Set, cancel or show the sleep timer of a running spotifyd via D-Bus
+    #[cfg(feature = "dbus_mpris")]
+    Sleep(SleepArgs),
+}
+
+#[cfg(feature = "dbus_mpris")]
+#[derive(Debug, Args)]
+pub struct SleepArgs {
+    /// Pause playback after this many minutes
+    #[arg(conflicts_with_all = ["end_of_track", "cancel"])]
+    pub minutes: Option<u32>,
+
+    /// Pause playback after the current track or episode
+    #[arg(long, conflicts_with = "cancel")]
+    pub end_of_track: bool,
+
+    /// Cancel the sleep timer
+    #[arg(long)]
+    pub cancel: bool,
+
+    /// The `bus_name` of the device, if several devices are running
+    #[arg(long, value_name = "NAME")]
+    pub bus_name: Option<String>,
+}
+
+#[cfg(unix)]
+#[derive(Debug, Args)]
+pub struct SnapcastControlArgs {
+    /// The control socket of the running spotifyd (its `snapcast_socket`)
+    #[arg(long, value_name = "PATH")]
+    pub socket: PathBuf,
+
+    /// The arguments passed by snapserver, which are ignored
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true, hide = true)]
+    pub snapserver_args: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct AuthenticateArgs {
+    /// The port to use for the OAuth redirect
+    #[arg(long, default_value_t = 8000)]
+    pub oauth_port: u16,
+
+    /// The host the OAuth redirect points to, e.g. a LAN address of this machine
+    #[arg(long, default_value = "127.0.0.1", value_name = "HOST")]
+    pub redirect_host: String,
+
+    /// Don't open a browser, but accept the pasted redirect URL or code on stdin
+    #[arg(long)]
+    pub headless: bool,
+
+    /// Store the credentials as a named account instead of the default one
+    #[arg(long, value_name = "LABEL")]
+    pub account: Option<String>,
+
+    #[command(subcommand)]
+    pub action: Option<AuthAction>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AuthAction {
+    /// Show the stored credentials and check whether they still work
+    Status {
+        /// Don't try to log in with the stored credentials
+        #[arg(long)]
+        offline: bool,
+    },
+    /// Remove stored credentials
+    Logout {
+        #[command(flatten)]
+        credentials: CredentialsSelector,
+
+        /// Remove all stored credentials
+        #[arg(long, conflicts_with_all = ["account", "zeroconf"])]
+        all: bool,
     },
+    /// Write stored credentials to a file or stdout
+    Export {
+        #[command(flatten)]
+        credentials: CredentialsSelector,
+
+        /// The file to write to (default: stdout)
+        #[arg(long, short, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Store credentials that have been exported before
+    Import {
+        #[command(flatten)]
+        credentials: CredentialsSelector,
+
+        /// The file to read from (default: stdin)
+        #[arg(long, short, value_name = "PATH")]
+        input: Option<PathBuf>,
+    },
+}
+
+/// Selects one set of stored credentials. Without any flag, the default OAuth account is used.
+#[derive(Debug, Args)]
+pub struct CredentialsSelector {
+    /// Use the named account
+    #[arg(long, value_name = "LABEL")]
+    pub account: Option<String>,
+
+    /// Use the credentials remembered from the last discovery login
+    #[arg(long, conflicts_with = "account")]
+    pub zeroconf: bool,
 }
 
 // A struct that holds all allowed config fields.
@@ -271,6 +451,18 @@ pub struct SharedConfigValues {
     #[arg(long, value_name = "BYTES")]
     max_cache_size: Option<u64>,
 
+    /// The account (stored with `spotifyd authenticate --account`) to log in with
+    #[arg(long, value_name = "LABEL")]
+    account: Option<String>,
+
+    /// A file with credentials in the format of `spotifyd auth export`, e.g. a mounted secret
+    #[arg(long, value_name = "PATH", conflicts_with = "credentials_cmd")]
+    credentials_file: Option<PathBuf>,
+
+    /// A command that prints credentials in the format of `spotifyd auth export`
+    #[arg(long, value_name = "CMD")]
+    credentials_cmd: Option<String>,
+
     /// Disable the use of audio cache
     #[arg(
         long,
@@ -291,7 +483,31 @@ pub struct SharedConfigValues {
     #[serde(alias = "volume-control")]
     volume_controller: Option<VolumeController>,
 
-    /// The audio device (or pipe file)
+    /// The command that sets the volume, which is appended in percent (with `command`)
+    #[arg(long, value_name = "CMD")]
+    set_volume_cmd: Option<String>,
+
+    /// The command that prints the volume in percent (with `command`)
+    #[arg(long, value_name = "CMD")]
+    get_volume_cmd: Option<String>,
+
+    /// How the volume maps to the loudness with `softvol` and `alsa` (default: log)
+    #[arg(value_enum, long)]
+    volume_curve: Option<VolumeCurve>,
+
+    /// The dB range of the `log` and `cubic` volume curves (default: 60)
+    #[arg(long, value_name = "DB")]
+    volume_range_db: Option<f64>,
+
+    /// The volume change in percent of volume up and down (default: 64 steps)
+    #[arg(long, value_name = "PERCENT")]
+    volume_step: Option<u8>,
+
+    /// The volume in percent that full volume in Spotify corresponds to (default: 100)
+    #[arg(long, value_name = "PERCENT")]
+    max_volume: Option<u8>,
+
+    /// The audio device (or pipe file, or address to stream to)
     #[arg(long)]
     device: Option<String>,
 
@@ -299,6 +515,10 @@ pub struct SharedConfigValues {
     #[arg(long, short)]
     device_name: Option<String>,
 
+    /// The id that identifies the device in Spotify (default: generated and stored in the cache)
+    #[arg(long, value_name = "ID")]
+    device_id: Option<String>,
+
     /// The bitrate of the streamed audio data
     #[arg(long, short = 'B', value_parser = bitrate_parser())]
     bitrate: Option<Bitrate>,
@@ -307,6 +527,34 @@ pub struct SharedConfigValues {
     #[arg(value_enum, long)]
     audio_format: Option<AudioFormat>,
 
+    /// Play on several outputs at once, instead of `backend` and `device` (config file only)
+    #[arg(skip)]
+    outputs: Option<Vec<OutputConfig>>,
+
+    /// The outputs to use if `backend` and `device` fail, in order (config file only)
+    #[arg(skip)]
+    fallback_outputs: Option<Vec<OutputConfig>>,
+
+    /// The equalizer preset to start with
+    #[arg(long, value_name = "NAME")]
+    equalizer_preset: Option<String>,
+
+    /// The equalizer presets by name (config file only)
+    #[arg(skip)]
+    equalizer_presets: Option<BTreeMap<String, EqualizerPreset>>,
+
+    /// Crossfade between tracks for this many seconds, up to 12 (default: 0, off)
+    #[arg(long, value_name = "SECONDS")]
+    crossfade_seconds: Option<u8>,
+
+    /// Fade in and out on play, pause, stop and seek for this many milliseconds (default: 0, off)
+    #[arg(long, value_name = "MS")]
+    fade_ms: Option<u16>,
+
+    /// Remember the volume per user or client in the cache and restore it when they take over
+    #[arg(value_enum, long)]
+    remember_volume: Option<RememberVolume>,
+
     /// Initial volume between 0 and 100
     #[arg(long)]
     #[serde(deserialize_with = "number_or_string", default)]
@@ -335,6 +583,59 @@ pub struct SharedConfigValues {
     )]
     disable_discovery: Option<bool>,
 
+    /// Only accept discovery logins of these Spotify users
+    #[arg(long, value_name = "USERS", value_delimiter = ',')]
+    allowed_users: Option<Vec<String>>,
+
+    /// Reject discovery logins of these Spotify users
+    #[arg(long, value_name = "USERS", value_delimiter = ',')]
+    denied_users: Option<Vec<String>>,
+
+    /// Only accept discovery logins of the user of the stored or configured credentials
+    #[arg(
+        long,
+        default_missing_value("true"),
+        require_equals = true,
+        num_args(0..=1),
+        value_name = "BOOL"
+    )]
+    discovery_owner_only: Option<bool>,
+
+    /// Only advertise the device on these network interfaces
+    #[arg(long, value_name = "INTERFACES", value_delimiter = ',')]
+    zeroconf_interfaces: Option<Vec<String>>,
+
+    /// Only advertise the device on this address
+    #[arg(long, value_name = "ADDRESS")]
+    zeroconf_bind_address: Option<IpAddr>,
+
+    /// The IP version used to advertise the device
+    #[arg(value_enum, long)]
+    zeroconf_ip_family: Option<IpFamily>,
+
+    /// A socket for Snapcast control scripts (see `spotifyd snapcast-control`)
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH")]
+    snapcast_socket: Option<PathBuf>,
+
+    /// Lower the audio by this many dB on `SIGUSR1`, until `SIGUSR2` (default with duck_seconds: 12)
+    #[cfg(unix)]
+    #[arg(long, value_name = "DB")]
+    duck_db: Option<f64>,
+
+    /// Return from ducking after this many seconds, instead of on `SIGUSR2`
+    #[cfg(unix)]
+    #[arg(long, value_name = "SECONDS")]
+    duck_seconds: Option<u32>,
+
+    /// When a discovery login may replace an active session
+    #[arg(value_enum, long)]
+    takeover_policy: Option<TakeoverPolicy>,
+
+    /// How long the device must be idle before it can be taken over with the idle policy
+    #[arg(long, value_name = "MINUTES")]
+    takeover_idle_minutes: Option<u64>,
+
     /// The port used for the Spotify Connect discovery
     #[arg(long)]
     zeroconf_port: Option<u16>,
@@ -347,6 +648,16 @@ pub struct SharedConfigValues {
     #[arg(value_enum, long)]
     device_type: Option<DeviceType>,
 
+    /// Announce the device as a speaker group, e.g. if it feeds a multi-room system
+    #[arg(
+        long,
+        default_missing_value("true"),
+        require_equals = true,
+        num_args(0..=1),
+        value_name = "BOOL"
+    )]
+    is_group: Option<bool>,
+
     /// Start playing similar songs after your music has ended
     #[arg(
         long,
@@ -389,7 +700,7 @@ pub struct MprisConfig {
 }
 
 #[cfg(feature = "alsa_backend")]
-#[derive(Debug, Default, Clone, Deserialize, Args, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, Deserialize, Args, PartialEq)]
 pub struct AlsaConfig {
     /// The control device
     #[arg(long)]
@@ -398,12 +709,108 @@ pub struct AlsaConfig {
     /// The mixer to use
     #[arg(long)]
     pub(crate) mixer: Option<String>,
+
+    /// The lowest volume of the alsa mixer in dB, below which it is muted
+    #[arg(long, value_name = "DB", allow_negative_numbers = true)]
+    pub(crate) volume_min_db: Option<f64>,
+
+    /// The highest volume of the alsa mixer in dB
+    #[arg(long, value_name = "DB", allow_negative_numbers = true)]
+    pub(crate) volume_max_db: Option<f64>,
+}
+
+/// An audio output in the `outputs` list.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OutputConfig {
+    /// The audio backend, or the default one
+    #[serde(deserialize_with = "deserialize_backend", default)]
+    pub(crate) backend: Option<String>,
+
+    /// The audio device (or pipe file, or address to stream to)
+    pub(crate) device: Option<String>,
+
+    /// The audio format, or `audio_format`
+    pub(crate) format: Option<AudioFormat>,
+}
+
+/// The kind of an equalizer filter.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterType {
+    Peaking,
+    LowShelf,
+    HighShelf,
+    LowPass,
+    HighPass,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    Left,
+    Right,
+}
+
+/// A biquad filter of an equalizer preset.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FilterConfig {
+    #[serde(rename = "type")]
+    pub(crate) kind: FilterType,
+
+    /// The center or corner frequency in Hz
+    pub(crate) freq: f64,
+
+    /// The quality factor, i.e. how narrow the filter is
+    #[serde(default = "default_filter_q")]
+    pub(crate) q: f64,
+
+    /// The gain of peaking and shelf filters
+    #[serde(default)]
+    pub(crate) gain_db: f64,
+
+    /// Only filter this channel
+    pub(crate) channel: Option<Channel>,
+}
+
+fn default_filter_q() -> f64 {
+    std::f64::consts::FRAC_1_SQRT_2
+}
+
+/// An equalizer preset in the `equalizer_presets` table.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EqualizerPreset {
+    /// The gain applied before the filters, usually negative to avoid clipping
+    #[serde(default)]
+    pub(crate) preamp_db: f64,
+
+    #[serde(default)]
+    pub(crate) filters: Vec<FilterConfig>,
+}
+
+/// A Spotify Connect device defined by a `[[device]]` table.
+///
+/// Values that are not set here are taken from the `global` and `spotifyd` sections.
+#[derive(Clone, Default, Debug, Deserialize, PartialEq)]
+pub struct DeviceConfig {
+    /// The subdirectory of `cache_path` that holds this device's cache
+    cache_subdir: Option<String>,
+
+    /// The suffix of the D-Bus names requested for this device
+    bus_name: Option<String>,
+
+    #[serde(flatten)]
+    values: SharedConfigValues,
 }
 
 #[derive(Debug, Default, Deserialize)]
 pub struct FileConfig {
     global: Option<SharedConfigValues>,
     spotifyd: Option<SharedConfigValues>,
+    #[serde(default, rename = "device")]
+    devices: Vec<DeviceConfig>,
 }
 
 impl FileConfig {
@@ -433,7 +840,7 @@ fn get_known_config_problem(path: &serde_ignored::Path<'_>) -> Option<KnownConfi
         #[cfg(not(feature = "alsa_backend"))]
         (
             KnownConfigProblem::MissingFeature("alsa_backend"),
-            &["control", "mixer"],
+            &["control", "mixer", "volume_min_db", "volume_max_db"],
         ),
         #[cfg(not(feature = "dbus_mpris"))]
         (
@@ -482,7 +889,7 @@ impl CliConfig {
         };
 
         let toml_de = toml::Deserializer::parse(&content)?;
-        let config_content: FileConfig = serde_ignored::deserialize(toml_de, |path| {
+        let mut config_content: FileConfig = serde_ignored::deserialize(toml_de, |path| {
             if let Some(problem) = get_known_config_problem(&path) {
                 match problem {
                     KnownConfigProblem::MissingFeature(feature) => {
@@ -501,6 +908,8 @@ impl CliConfig {
             }
         })?;
 
+        self.devices = std::mem::take(&mut config_content.devices);
+
         // The call to get_merged_sections consumes the FileConfig!
         if let Some(merged_sections) = config_content.get_merged_sections() {
             self.shared_config.merge_with(merged_sections);
@@ -511,33 +920,42 @@ impl CliConfig {
 }
 
 impl SharedConfigValues {
-    pub fn get_cache(&self, for_oauth: bool) -> color_eyre::Result<Cache> {
+    #[cfg(feature = "dbus_mpris")]
+    pub(crate) fn dbus_type(&self) -> DBusType {
+        self.mpris_config.dbus_type.unwrap_or(DBusType::Session)
+    }
+
+    pub(crate) fn get_cache_dir(&self) -> color_eyre::Result<Cow<'_, Path>> {
         let Some(cache_path) = self.cache_path.as_deref().map(Cow::Borrowed).or_else(|| {
             ProjectDirs::from("", "", "spotifyd")
                 .map(|dirs| Cow::Owned(dirs.cache_dir().to_path_buf()))
         }) else {
             bail!("Failed to determine cache directory, please specify one manually");
         };
+        Ok(cache_path)
+    }
 
-        if for_oauth {
-            let mut creds_path = cache_path.into_owned();
-            creds_path.push("oauth");
-            Cache::new(Some(creds_path), None, None, None)
-        } else {
-            let audio_cache = !self.no_audio_cache.unwrap_or(false);
-
-            let mut creds_path = cache_path.to_path_buf();
-            creds_path.push("zeroconf");
-            Cache::new(
-                Some(creds_path.as_path()),
-                Some(cache_path.as_ref()),
-                audio_cache.then_some(cache_path.as_ref()),
-                self.max_cache_size,
-            )
-        }
+    pub fn get_cache(&self) -> color_eyre::Result<Cache> {
+        let cache_path = self.get_cache_dir()?;
+        let audio_cache = !self.no_audio_cache.unwrap_or(false);
+
+        let mut creds_path = cache_path.to_path_buf();
+        creds_path.push(ZEROCONF_CREDENTIALS_DIR);
+        Cache::new(
+            Some(creds_path.as_path()),
+            Some(cache_path.as_ref()),
+            audio_cache.then_some(cache_path.as_ref()),
+            self.max_cache_size,
+        )
         .wrap_err("Failed to initialize cache")
     }
 
+    pub fn get_accounts(&self) -> color_eyre::Result<Accounts> {
+        let mut oauth_path = self.get_cache_dir()?.into_owned();
+        oauth_path.push("oauth");
+        Ok(Accounts::new(oauth_path))
+    }
+
     pub fn proxy_url(&self) -> Option<Url> {
         match &self.proxy {
             Some(s) => match Url::parse(s) {
@@ -571,29 +989,57 @@ impl SharedConfigValues {
         // Handles Option<T> merging.
         merge!(self; and other => {
             backend,
+            outputs,
+            fallback_outputs,
+            equalizer_preset,
+            equalizer_presets,
+            crossfade_seconds,
+            fade_ms,
             volume_normalisation,
             normalisation_pregain,
             bitrate,
             initial_volume,
+            remember_volume,
             device_name,
+            device_id,
             device,
             volume_controller,
+            set_volume_cmd,
+            get_volume_cmd,
+            volume_curve,
+            volume_range_db,
+            volume_step,
+            max_volume,
             cache_path,
             no_audio_cache,
             on_song_change_hook,
             disable_discovery,
+            allowed_users,
+            denied_users,
+            discovery_owner_only,
+            takeover_policy,
+            takeover_idle_minutes,
             zeroconf_port,
+            zeroconf_interfaces,
+            zeroconf_bind_address,
+            zeroconf_ip_family,
             proxy,
             device_type,
+            is_group,
             max_cache_size,
+            account,
+            credentials_file,
+            credentials_cmd,
             audio_format,
             autoplay
         });
 
+        #[cfg(unix)]
+        merge!(self; and other => {snapcast_socket, duck_db, duck_seconds});
         #[cfg(feature = "dbus_mpris")]
         merge!(self.mpris_config; and other.mpris_config => {use_mpris, dbus_type});
         #[cfg(feature = "alsa_backend")]
-        merge!(self.alsa_config; and other.alsa_config => {mixer, control});
+        merge!(self.alsa_config; and other.alsa_config => {mixer, control, volume_min_db, volume_max_db});
     }
 }
 
@@ -617,44 +1063,296 @@ fn device_id(name: &str) -> String {
     hex::encode(Sha1::digest(name.as_bytes()))
 }
 
+/// The file in the cache directory that stores the device id.
+const DEVICE_ID_FILE: &str = "device_id";
+
+/// Returns the device id stored in the cache directory.
+///
+/// On first start, the id is derived from the device name (as it has been done before the id was
+/// stored) and saved, so that the device keeps its identity when it is renamed later on.
+fn persistent_device_id(cache_dir: Option<&Path>, name: &str) -> String {
+    let Some(cache_dir) = cache_dir else {
+        return device_id(name);
+    };
+    let path = cache_dir.join(DEVICE_ID_FILE);
+    match fs::read_to_string(&path) {
+        Ok(id) if !id.trim().is_empty() => return id.trim().to_string(),
+        Ok(_) => warn!("Ignoring empty device id file {path:?}"),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+        Err(err) => {
+            warn!("Failed to read device id from {path:?}: {err}");
+            return device_id(name);
+        }
+    }
+
+    let id = device_id(name);
+    if let Err(err) = fs::create_dir_all(cache_dir).and_then(|_| fs::write(&path, &id)) {
+        warn!("Failed to store device id in {path:?}: {err}");
+    }
+    id
+}
+
+/// The file in the cache directory that stores a name set at runtime.
+const DEVICE_NAME_FILE: &str = "device_name";
+
+/// A device name set at runtime, which is stored in the cache directory next to the device id.
+///
+/// The configured name it replaced is stored as well, so that changing `device_name` in the
+/// config still takes effect.
+pub(crate) struct StoredName {
+    path: PathBuf,
+    configured: String,
+}
+
+impl StoredName {
+    fn new(cache_dir: &Path, configured: String) -> Self {
+        Self {
+            path: cache_dir.join(DEVICE_NAME_FILE),
+            configured,
+        }
+    }
+
+    /// The stored name, unless the configured name has changed since it has been stored.
+    fn load(&self) -> Option<String> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(err) => {
+                warn!("Failed to read the device name from {:?}: {err}", self.path);
+                return None;
+            }
+        };
+        let (configured, name) = content.trim_end_matches('\n').split_once('\n')?;
+        (configured == self.configured && !name.trim().is_empty()).then(|| name.to_string())
+    }
+
+    pub(crate) fn store(&self, name: &str) {
+        let content = format!("{}\n{name}\n", self.configured);
+        let dir = self
+            .path
+            .parent()
+            .expect("the file is in the cache directory");
+        if let Err(err) = fs::create_dir_all(dir).and_then(|_| fs::write(&self.path, content)) {
+            warn!("Failed to store the device name in {:?}: {err}", self.path);
+        }
+    }
+}
+
+/// Chooses the default ditherer for an audio format, the same way librespot does.
+fn default_ditherer(audio_format: LSAudioFormat) -> Option<DithererBuilder> {
+    match audio_format {
+        LSAudioFormat::S16 | LSAudioFormat::S24 | LSAudioFormat::S24_3 => {
+            Some(mk_ditherer::<TriangularDitherer>)
+        }
+        _ => None,
+    }
+}
+
 pub(crate) struct SpotifydConfig {
     pub(crate) cache: Option<Cache>,
-    pub(crate) oauth_cache: Option<Cache>,
+    pub(crate) accounts: Option<Accounts>,
+    pub(crate) account: Option<String>,
+    pub(crate) credentials_source: Option<CredentialsSource>,
     pub(crate) backend: Option<String>,
     pub(crate) audio_device: Option<String>,
     pub(crate) audio_format: LSAudioFormat,
+    pub(crate) outputs: Vec<OutputConfig>,
+    pub(crate) fallback_outputs: Vec<OutputConfig>,
+    pub(crate) equalizer_preset: Option<String>,
+    pub(crate) equalizer_presets: BTreeMap<String, EqualizerPreset>,
+    pub(crate) crossfade_seconds: u8,
+    pub(crate) fade: Duration,
     pub(crate) volume_controller: VolumeController,
+    pub(crate) set_volume_cmd: Option<String>,
+    pub(crate) get_volume_cmd: Option<String>,
+    /// The volume curve, or the default one of the volume controller.
+    pub(crate) volume_curve: Option<VolumeCtrl>,
+    pub(crate) volume_steps: u16,
+    pub(crate) max_volume: u16,
     pub(crate) initial_volume: u16,
+    pub(crate) remember_volume: Option<RememberVolume>,
+    pub(crate) cache_dir: Option<PathBuf>,
     pub(crate) device_name: String,
+    /// Where a name set at runtime is stored.
+    pub(crate) stored_name: Option<StoredName>,
     pub(crate) player_config: PlayerConfig,
     pub(crate) session_config: SessionConfig,
     pub(crate) onevent: Option<String>,
     #[cfg(unix)]
     pub(crate) pid: Option<String>,
+    #[cfg(unix)]
+    pub(crate) snapcast_socket: Option<PathBuf>,
+    /// How many dB to duck by on `SIGUSR1`, `None` if the signals don't duck.
+    #[cfg(unix)]
+    pub(crate) duck_db: Option<f64>,
+    #[cfg(unix)]
+    pub(crate) duck_duration: Option<Duration>,
     pub(crate) shell: String,
     pub(crate) discovery: bool,
+    pub(crate) allowed_users: Vec<String>,
+    pub(crate) denied_users: Vec<String>,
+    pub(crate) discovery_owner_only: bool,
+    pub(crate) takeover_policy: TakeoverPolicy,
+    pub(crate) takeover_idle_timeout: Duration,
     pub(crate) zeroconf_port: Option<u16>,
+    pub(crate) zeroconf_interfaces: Vec<String>,
+    pub(crate) zeroconf_bind_address: Option<IpAddr>,
+    pub(crate) zeroconf_ip_family: IpFamily,
     pub(crate) device_type: LSDeviceType,
+    pub(crate) is_group: bool,
     #[cfg(feature = "dbus_mpris")]
     pub(crate) mpris: MprisConfig,
     #[cfg(feature = "alsa_backend")]
     pub(crate) alsa_config: AlsaConfig,
+    pub(crate) bus_name: Option<String>,
+}
+
+/// Returns the config of every device that should be run by this process.
+///
+/// Without any `[[device]]` tables, this is just a single device.
+pub(crate) fn get_internal_configs(
+    mut config: CliConfig,
+) -> color_eyre::Result<Vec<SpotifydConfig>> {
+    let devices = std::mem::take(&mut config.devices);
+    if devices.is_empty() {
+        return Ok(vec![get_internal_config(config)]);
+    }
+
+    let base_cache_dir = config
+        .shared_config
+        .get_cache_dir()
+        .map(Cow::into_owned)
+        .ok();
+    let accounts = config.shared_config.get_accounts().ok();
+
+    let mut names = Vec::with_capacity(devices.len());
+    let mut ports = Vec::new();
+    #[cfg(unix)]
+    let mut sockets = Vec::new();
+    let mut cache_paths = Vec::new();
+    let mut bus_names = Vec::new();
+    let mut internal_configs = Vec::with_capacity(devices.len());
+    for (index, device) in devices.into_iter().enumerate() {
+        let Some(name) = device
+            .values
+            .device_name
+            .clone()
+            .filter(|s| !s.trim().is_empty())
+        else {
+            bail!("the [[device]] table #{} has no device_name", index + 1);
+        };
+        if names.contains(&name) {
+            bail!("the device name '{name}' is used by several [[device]] tables");
+        }
+        names.push(name.clone());
+
+        let mut values = device.values;
+        if values.cache_path.is_none() {
+            let subdir = device
+                .cache_subdir
+                .unwrap_or_else(|| sanitize_identifier(&name));
+            values.cache_path = base_cache_dir.as_ref().map(|dir| dir.join(subdir));
+        }
+        values.merge_with(config.shared_config.clone());
+        let bus_name = device
+            .bus_name
+            .unwrap_or_else(|| sanitize_identifier(&name));
+
+        // values that can only be used by one device, 0 is a random port
+        check_unique(
+            &mut ports,
+            "zeroconf_port",
+            values.zeroconf_port.filter(|&port| port != 0),
+        )?;
+        #[cfg(unix)]
+        check_unique(
+            &mut sockets,
+            "snapcast_socket",
+            values.snapcast_socket.clone(),
+        )?;
+        check_unique(&mut cache_paths, "cache_path", values.cache_path.clone())?;
+        check_unique(&mut bus_names, "bus_name", Some(bus_name.clone()))?;
+
+        let mut internal_config = get_internal_config(CliConfig {
+            #[cfg(unix)]
+            pid: config.pid.clone(),
+            shared_config: values,
+            ..Default::default()
+        });
+        // all devices share the accounts stored with `spotifyd authenticate`
+        internal_config.accounts = accounts.clone();
+        internal_config.bus_name = Some(bus_name);
+        internal_configs.push(internal_config);
+    }
+    Ok(internal_configs)
+}
+
+/// Fails if the value of `key` is used by another device already, and remembers it otherwise.
+fn check_unique<T: PartialEq + fmt::Debug>(
+    used: &mut Vec<T>,
+    key: &str,
+    value: Option<T>,
+) -> color_eyre::Result<()> {
+    let Some(value) = value else {
+        return Ok(());
+    };
+    if used.contains(&value) {
+        bail!(
+            "the {key} {value:?} is used by several [[device]] tables, set a separate one in each table"
+        );
+    }
+    used.push(value);
+    Ok(())
+}
+
+/// Turns a device name into something that can be used as directory or D-Bus name element.
+fn sanitize_identifier(name: &str) -> String {
+    let sanitized: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{sanitized}")
+    } else {
+        sanitized
+    }
 }
 
 pub(crate) fn get_internal_config(config: CliConfig) -> SpotifydConfig {
-    let (cache, oauth_cache) = match (
-        config.shared_config.get_cache(false),
-        config.shared_config.get_cache(true),
+    let (cache, accounts) = match (
+        config.shared_config.get_cache(),
+        config.shared_config.get_accounts(),
     ) {
-        (Ok(cache), Ok(oauth_cache)) => (Some(cache), Some(oauth_cache)),
+        (Ok(cache), Ok(accounts)) => (Some(cache), Some(accounts)),
         (a, b) => {
             // at least one of the results are err
-            let err = a.or(b).map(|_| ()).unwrap_err();
+            let err = a.map(|_| ()).and(b.map(|_| ())).unwrap_err();
             warn!("{err}");
             (None, None)
         }
     };
     let proxy_url = config.shared_config.proxy_url();
+    let cache_dir = config
+        .shared_config
+        .get_cache_dir()
+        .ok()
+        .map(Cow::into_owned);
+
+    let credentials_source = match (
+        config.shared_config.credentials_file,
+        config.shared_config.credentials_cmd,
+    ) {
+        (Some(path), cmd) => {
+            if cmd.is_some() {
+                warn!(
+                    "Both credentials_file and credentials_cmd are set, ignoring credentials_cmd"
+                );
+            }
+            Some(CredentialsSource::File(path))
+        }
+        (None, Some(cmd)) => Some(CredentialsSource::Command(cmd)),
+        (None, None) => None,
+    };
 
     let bitrate: LSBitrate = config
         .shared_config
@@ -688,13 +1386,91 @@ pub(crate) fn get_internal_config(config: CliConfig) -> SpotifydConfig {
         .map(|volume| (volume as i32 * (u16::MAX as i32) / 100) as u16)
         .unwrap_or((default_initial_volume * (u16::MAX as i32) / 100) as u16);
 
+    let volume_range_db = config
+        .shared_config
+        .volume_range_db
+        .filter(|range| {
+            if *range > 0.0 {
+                true
+            } else {
+                warn!("volume_range_db must be greater than 0");
+                false
+            }
+        })
+        .unwrap_or(VolumeCtrl::DEFAULT_DB_RANGE);
+    let volume_curve = config.shared_config.volume_curve.map(|curve| match curve {
+        VolumeCurve::Linear => VolumeCtrl::Linear,
+        VolumeCurve::Log => VolumeCtrl::Log(volume_range_db),
+        VolumeCurve::Cubic => VolumeCtrl::Cubic(volume_range_db),
+    });
+
+    let volume_steps = config
+        .shared_config
+        .volume_step
+        .filter(|step| {
+            if (1..=100).contains(step) {
+                true
+            } else {
+                warn!("volume_step must be in range 1..100");
+                false
+            }
+        })
+        .map(|step| (100 / step) as u16)
+        .unwrap_or(64);
+
+    let max_volume = config
+        .shared_config
+        .max_volume
+        .filter(|val| {
+            if (1..=100).contains(val) {
+                true
+            } else {
+                warn!("max_volume must be in range 1..100");
+                false
+            }
+        })
+        .map(|volume| (volume as u32 * u16::MAX as u32 / 100) as u16)
+        .unwrap_or(u16::MAX);
+
+    let crossfade_seconds = config
+        .shared_config
+        .crossfade_seconds
+        .filter(|seconds| {
+            if *seconds <= MAX_CROSSFADE_SECONDS {
+                true
+            } else {
+                warn!("crossfade_seconds must be in range 0..{MAX_CROSSFADE_SECONDS}");
+                false
+            }
+        })
+        .unwrap_or(0);
+
     let device_name = config
         .shared_config
         .device_name
         .filter(|s| !s.trim().is_empty())
         .unwrap_or_else(|| format!("{}@{}", "Spotifyd", gethostname().to_string_lossy()));
 
-    let device_id = device_id(&device_name);
+    let device_id = match config
+        .shared_config
+        .device_id
+        .filter(|id| !id.trim().is_empty())
+    {
+        Some(id) => id,
+        None => persistent_device_id(cache_dir.as_deref(), &device_name),
+    };
+
+    // a name set at runtime replaces the configured one
+    let stored_name = cache_dir
+        .as_deref()
+        .map(|dir| StoredName::new(dir, device_name.clone()));
+    let device_name = match stored_name.as_ref().and_then(StoredName::load) {
+        Some(name) => {
+            info!("Using the device name '{name}', which has been set at runtime");
+            name
+        }
+        None => device_name,
+    };
 
     let normalisation_pregain = config.shared_config.normalisation_pregain.unwrap_or(0.0);
 
@@ -716,13 +1492,7 @@ pub(crate) fn get_internal_config(config: CliConfig) -> SpotifydConfig {
         "sh".to_string()
     });
 
-    // choose default ditherer the same way librespot does
-    let ditherer: Option<DithererBuilder> = match audio_format {
-        LSAudioFormat::S16 | LSAudioFormat::S24 | LSAudioFormat::S24_3 => {
-            Some(mk_ditherer::<TriangularDitherer>)
-        }
-        _ => None,
-    };
+    let ditherer = default_ditherer(audio_format);
 
     // TODO: when we were on librespot 0.1.5, all PlayerConfig values were available in the
     //  Spotifyd config. The upgrade to librespot 0.2.0 introduces new config variables, and we
@@ -738,13 +1508,29 @@ pub(crate) fn get_internal_config(config: CliConfig) -> SpotifydConfig {
 
     SpotifydConfig {
         cache,
-        oauth_cache,
+        accounts,
+        account: config.shared_config.account,
+        credentials_source,
         backend: config.shared_config.backend,
         audio_device: config.shared_config.device,
         audio_format,
+        outputs: config.shared_config.outputs.unwrap_or_default(),
+        fallback_outputs: config.shared_config.fallback_outputs.unwrap_or_default(),
+        equalizer_preset: config.shared_config.equalizer_preset,
+        equalizer_presets: config.shared_config.equalizer_presets.unwrap_or_default(),
+        crossfade_seconds,
+        fade: Duration::from_millis(config.shared_config.fade_ms.unwrap_or(0).into()),
         volume_controller,
+        set_volume_cmd: config.shared_config.set_volume_cmd,
+        get_volume_cmd: config.shared_config.get_volume_cmd,
+        volume_curve,
+        volume_steps,
+        max_volume,
         initial_volume,
+        remember_volume: config.shared_config.remember_volume,
+        cache_dir,
         device_name,
+        stored_name,
         player_config: pc,
         session_config: SessionConfig {
             autoplay: config.shared_config.autoplay,
@@ -756,14 +1542,38 @@ pub(crate) fn get_internal_config(config: CliConfig) -> SpotifydConfig {
         onevent: config.shared_config.on_song_change_hook,
         shell,
         discovery: !config.shared_config.disable_discovery.unwrap_or(false),
+        allowed_users: config.shared_config.allowed_users.unwrap_or_default(),
+        denied_users: config.shared_config.denied_users.unwrap_or_default(),
+        discovery_owner_only: config.shared_config.discovery_owner_only.unwrap_or(false),
+        takeover_policy: config.shared_config.takeover_policy.unwrap_or_default(),
+        takeover_idle_timeout: Duration::from_secs(
+            config.shared_config.takeover_idle_minutes.unwrap_or(5) * 60,
+        ),
         zeroconf_port: config.shared_config.zeroconf_port,
+        zeroconf_interfaces: config.shared_config.zeroconf_interfaces.unwrap_or_default(),
+        zeroconf_bind_address: config.shared_config.zeroconf_bind_address,
+        zeroconf_ip_family: config.shared_config.zeroconf_ip_family.unwrap_or_default(),
         device_type,
+        is_group: config.shared_config.is_group.unwrap_or(false),
         #[cfg(unix)]
         pid,
+        #[cfg(unix)]
+        snapcast_socket: config.shared_config.snapcast_socket,
+        #[cfg(unix)]
+        duck_db: config
+            .shared_config
+            .duck_db
+            .or(config.shared_config.duck_seconds.map(|_| 12.0)),
+        #[cfg(unix)]
+        duck_duration: config
+            .shared_config
+            .duck_seconds
+            .map(|seconds| Duration::from_secs(seconds.into())),
         #[cfg(feature = "dbus_mpris")]
         mpris: config.shared_config.mpris_config,
         #[cfg(feature = "alsa_backend")]
         alsa_config: config.shared_config.alsa_config,
+        bus_name: None,
     }
 }
 
@@ -789,6 +1599,7 @@ mod tests {
         let file_config = FileConfig {
             global: Some(global_section),
             spotifyd: Some(spotifyd_section.clone()),
+            ..Default::default()
         };
         let merged_config = file_config.get_merged_sections().unwrap();
 
@@ -797,6 +1608,119 @@ mod tests {
         assert_eq!(merged_config, spotifyd_section);
     }
 
+    #[test]
+    fn test_device_tables() {
+        let config = r#"
+            [global]
+            backend = "pipe"
+
+            [[device]]
+            device_name = "Kitchen"
+            device = "/tmp/kitchen"
+
+            [[device]]
+            device_name = "Living Room"
+            cache_subdir = "living"
+            bus_name = "living"
+        "#;
+
+        let config: FileConfig = toml::from_str(config).expect("device tables should be valid");
+
+        assert_eq!(config.devices.len(), 2);
+        assert_eq!(
+            config.devices[0].values.device_name.as_deref(),
+            Some("Kitchen")
+        );
+        assert_eq!(
+            config.devices[0].values.device.as_deref(),
+            Some("/tmp/kitchen")
+        );
+        assert_eq!(config.devices[1].cache_subdir.as_deref(), Some("living"));
+        assert_eq!(config.devices[1].bus_name.as_deref(), Some("living"));
+    }
+
+    #[test]
+    fn test_unique_device_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let internal_configs = |devices: &str| {
+            let config = format!(
+                "[global]\ncache_path = '{}'\nzeroconf_port = 1234\n{devices}",
+                dir.path().display()
+            );
+            let config: FileConfig = toml::from_str(&config).unwrap();
+            get_internal_configs(CliConfig {
+                devices: config.devices,
+                shared_config: config.global.unwrap(),
+                ..Default::default()
+            })
+        };
+
+        let shared_port = internal_configs(
+            r#"
+            [[device]]
+            device_name = "Kitchen"
+
+            [[device]]
+            device_name = "Living Room"
+            "#,
+        );
+        assert!(shared_port.is_err());
+
+        let configs = internal_configs(
+            r#"
+            [[device]]
+            device_name = "Kitchen"
+            zeroconf_port = 1235
+
+            [[device]]
+            device_name = "Living Room"
+            zeroconf_port = 0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(configs.len(), 2);
+        assert_ne!(configs[0].cache_dir, configs[1].cache_dir);
+    }
+
+    #[test]
+    fn test_identifier_sanitizing() {
+        assert_eq!(sanitize_identifier("Kitchen"), "Kitchen");
+        assert_eq!(sanitize_identifier("Living Room"), "Living_Room");
+        assert_eq!(sanitize_identifier("2nd floor"), "_2nd_floor");
+    }
+
+    #[test]
+    fn test_persistent_device_id() {
+        let dir = tempfile::tempdir().unwrap();
+        // the cache directory doesn't exist yet
+        let cache_dir = dir.path().join("cache");
+
+        let id = persistent_device_id(Some(&cache_dir), "Kitchen");
+        assert_eq!(
+            id,
+            device_id("Kitchen"),
+            "the first id is derived from the name"
+        );
+        assert_eq!(
+            persistent_device_id(Some(&cache_dir), "Living Room"),
+            id,
+            "renaming must keep the stored id"
+        );
+    }
+
+    #[test]
+    fn test_stored_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let stored = StoredName::new(dir.path(), "Kitchen".to_string());
+        assert_eq!(stored.load(), None);
+
+        stored.store("Kitchen Speaker");
+        assert_eq!(stored.load().as_deref(), Some("Kitchen Speaker"));
+        // changing the configured name drops the one set at runtime
+        let changed = StoredName::new(dir.path(), "Kitchen 2".to_string());
+        assert_eq!(changed.load(), None);
+    }
+
     #[test]
     fn test_example_config() {
         let example_config = include_str!("../contrib/spotifyd.conf");
@@ -0,0 +1,441 @@
+//! Crossfading between tracks.
+//!
+//! When a track is about to end and the next one has been preloaded, the last seconds of the
+//! track are held back instead of being played. Once the next track starts, they are faded out
+//! over its start, which is faded in. If playback is paused or stopped in the meantime, the held
+//! back samples are dropped, after fading out over the length of a fade.
+
+use librespot_core::{Session, SpotifyUri};
+use librespot_metadata::audio::{AudioItem, UniqueFields};
+use librespot_playback::{
+    NUM_CHANNELS, SAMPLE_RATE,
+    audio_backend::{Sink, SinkResult},
+    convert::Converter,
+    decoder::AudioPacket,
+    dither::DithererBuilder,
+    player::{PlayerEvent, PlayerEventChannel},
+};
+use log::{debug, info, warn};
+use std::{
+    collections::VecDeque,
+    f64::consts::FRAC_PI_2,
+    mem,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU8, Ordering},
+    },
+    time::Duration,
+};
+
+/// The longest crossfade, as in the Spotify apps.
+pub(crate) const MAX_CROSSFADE_SECONDS: u8 = 12;
+/// How much earlier than needed to start holding back the end of a track, since its position
+/// is only known approximately.
+const HOLD_MARGIN_MS: u64 = 1000;
+const SAMPLES_PER_SECOND: u64 = SAMPLE_RATE as u64 * NUM_CHANNELS as u64;
+
+/// The track that is being played.
+struct Track {
+    uri: SpotifyUri,
+    duration_ms: u32,
+    /// The number of samples received before the start of the track, once it is known.
+    start: Option<i64>,
+    /// Whether to crossfade into the next track.
+    crossfade: bool,
+}
+
+#[derive(Default)]
+enum Phase {
+    #[default]
+    Normal,
+    /// The end of the track is held back, until the next one starts.
+    Holding(VecDeque<f64>),
+    /// The end of the previous track is mixed into the start of the next one.
+    Mixing { tail: Vec<f64>, pos: usize },
+}
+
+#[derive(Default)]
+struct State {
+    /// The number of samples received from the player.
+    received: u64,
+    track: Option<Track>,
+    phase: Phase,
+}
+
+/// The crossfade between tracks, which can be changed at runtime.
+pub(crate) struct Crossfade {
+    seconds: AtomicU8,
+    state: Mutex<State>,
+}
+
+impl Crossfade {
+    pub(crate) fn new(seconds: u8) -> Self {
+        Self {
+            seconds: AtomicU8::new(seconds),
+            state: Mutex::default(),
+        }
+    }
+
+    /// The length of the crossfade, zero if it is turned off.
+    pub(crate) fn seconds(&self) -> u8 {
+        self.seconds.load(Ordering::Relaxed)
+    }
+
+    #[cfg_attr(not(feature = "dbus_mpris"), expect(dead_code))]
+    pub(crate) fn set_seconds(&self, seconds: u8) {
+        let seconds = seconds.min(MAX_CROSSFADE_SECONDS);
+        info!("Setting the crossfade to {seconds} seconds");
+        self.seconds.store(seconds, Ordering::Relaxed);
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("crossfade has been poisoned")
+    }
+
+    fn track_changed(&self, uri: SpotifyUri, duration_ms: u32) {
+        let mut state = self.state();
+        if let Phase::Holding(tail) = mem::take(&mut state.phase) {
+            debug!("Crossfading into the next track");
+            state.phase = Phase::Mixing {
+                tail: tail.into(),
+                pos: 0,
+            };
+        }
+        state.track = Some(Track {
+            uri,
+            duration_ms,
+            start: None,
+            crossfade: false,
+        });
+    }
+
+    /// Relates the position of the current track to the samples received so far.
+    fn position(&self, uri: &SpotifyUri, position_ms: u32) {
+        let mut state = self.state();
+        let received = state.received as i64;
+        if let Some(track) = state.track.as_mut().filter(|track| track.uri == *uri) {
+            track.start = Some(received - ms_to_samples(position_ms as u64) as i64);
+        }
+    }
+
+    fn seeked(&self, uri: &SpotifyUri, position_ms: u32) {
+        // the held back or mixed samples are from before the seek
+        self.state().phase = Phase::Normal;
+        self.position(uri, position_ms);
+    }
+
+    /// Crossfades from the track into the next one, if it is still being played.
+    fn arm(&self, uri: &SpotifyUri) {
+        if let Some(track) = self
+            .state()
+            .track
+            .as_mut()
+            .filter(|track| track.uri == *uri)
+        {
+            track.crossfade = true;
+        }
+    }
+
+    /// Follows the player events, to find out when and whether to crossfade.
+    pub(crate) async fn watch(self: Arc<Self>, mut events: PlayerEventChannel, session: Session) {
+        let mut current: Option<Box<AudioItem>> = None;
+        while let Some(event) = events.recv().await {
+            match event {
+                PlayerEvent::TrackChanged { audio_item } => {
+                    self.track_changed(audio_item.track_id.clone(), audio_item.duration_ms);
+                    current = Some(audio_item);
+                }
+                PlayerEvent::Playing {
+                    track_id,
+                    position_ms,
+                    ..
+                }
+                | PlayerEvent::PositionCorrection {
+                    track_id,
+                    position_ms,
+                    ..
+                } => self.position(&track_id, position_ms),
+                PlayerEvent::Seeked {
+                    track_id,
+                    position_ms,
+                    ..
+                } => self.seeked(&track_id, position_ms),
+                // triggered by TimeToPreloadNextTrack, once the next track is ready
+                PlayerEvent::Preloading { track_id } => {
+                    let Some(current) = current.clone().filter(|_| self.seconds() > 0) else {
+                        continue;
+                    };
+                    let crossfade = self.clone();
+                    let session = session.clone();
+                    tokio::spawn(async move {
+                        match AudioItem::get_file(&session, track_id).await {
+                            Ok(next) if should_crossfade(&current, &next) => {
+                                crossfade.arm(&current.track_id)
+                            }
+                            Ok(_) => debug!("Not crossfading into '{}'", current.name),
+                            Err(err) => {
+                                warn!("Failed to get the metadata of the next track: {err}")
+                            }
+                        }
+                    });
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Holds back or mixes the samples, and returns the ones to be played.
+    fn process(&self, mut samples: Vec<f64>) -> Vec<f64> {
+        let window = self.seconds() as usize * SAMPLES_PER_SECOND as usize;
+        let mut state = self.state();
+        let state = &mut *state;
+        let first = state.received;
+        state.received += samples.len() as u64;
+
+        let hold_at = state
+            .track
+            .as_ref()
+            .filter(|track| track.crossfade && window > 0)
+            .and_then(|track| {
+                let hold_ms = (track.duration_ms as u64)
+                    .saturating_sub(self.seconds() as u64 * 1000 + HOLD_MARGIN_MS);
+                Some(track.start? + ms_to_samples(hold_ms) as i64)
+            });
+
+        let (phase, played) = match mem::take(&mut state.phase) {
+            Phase::Normal => match hold_at {
+                Some(hold_at) if hold_at < (first + samples.len() as u64) as i64 => {
+                    let split = (hold_at - first as i64).max(0) as usize;
+                    let split = split - split % NUM_CHANNELS as usize;
+                    let mut tail = VecDeque::from(samples.split_off(split));
+                    let excess = tail.len().saturating_sub(window);
+                    samples.extend(tail.drain(..excess));
+                    (Phase::Holding(tail), samples)
+                }
+                _ => (Phase::Normal, samples),
+            },
+            Phase::Holding(mut tail) => {
+                tail.extend(samples);
+                let excess = tail.len().saturating_sub(window);
+                let played = tail.drain(..excess).collect();
+                (Phase::Holding(tail), played)
+            }
+            Phase::Mixing { tail, mut pos } => {
+                let frames = (tail.len() / NUM_CHANNELS as usize).max(1) as f64;
+                for (sample, held) in samples.iter_mut().zip(&tail[pos..]) {
+                    // equal power curves, since the tracks are not correlated
+                    let t = (pos / NUM_CHANNELS as usize) as f64 / frames * FRAC_PI_2;
+                    *sample = *sample * t.sin() + held * t.cos();
+                    pos += 1;
+                }
+                if pos < tail.len() {
+                    (Phase::Mixing { tail, pos }, samples)
+                } else {
+                    (Phase::Normal, samples)
+                }
+            }
+        };
+        state.phase = phase;
+        played
+    }
+
+    /// Takes the held back end of the track, when playback is paused or stopped.
+    fn flush(&self) -> Vec<f64> {
+        match mem::take(&mut self.state().phase) {
+            Phase::Holding(tail) => tail.into(),
+            // the rest of the previous track, which is being faded out anyway
+            Phase::Normal | Phase::Mixing { .. } => Vec::new(),
+        }
+    }
+}
+
+fn ms_to_samples(ms: u64) -> u64 {
+    ms * SAMPLES_PER_SECOND / 1000
+}
+
+/// Whether to crossfade between two items, which isn't done for episodes and for consecutive
+/// tracks of an album, that are often meant to be played without a gap.
+fn should_crossfade(current: &AudioItem, next: &AudioItem) -> bool {
+    let album_position = |item: &AudioItem| match &item.unique_fields {
+        UniqueFields::Track {
+            album,
+            disc_number,
+            number,
+            ..
+        } => Some((album.clone(), *disc_number, *number)),
+        UniqueFields::Local {
+            album: Some(album),
+            disc_number,
+            number: Some(number),
+            ..
+        } => Some((album.clone(), disc_number.unwrap_or(1), *number)),
+        UniqueFields::Local { .. } | UniqueFields::Episode { .. } => None,
+    };
+    match (album_position(current), album_position(next)) {
+        (Some((album, disc, number)), Some((next_album, next_disc, next_number))) => {
+            let consecutive = (next_disc == disc && next_number == number + 1)
+                || (next_disc == disc + 1 && next_number == 1);
+            album != next_album || !consecutive
+        }
+        _ => {
+            !matches!(current.unique_fields, UniqueFields::Episode { .. })
+                && !matches!(next.unique_fields, UniqueFields::Episode { .. })
+        }
+    }
+}
+
+struct CrossfadeSink {
+    sink: Box<dyn Sink>,
+    crossfade: Arc<Crossfade>,
+    /// How many of the held back samples are faded out on stop.
+    fade_len: usize,
+    /// Converts the faded out samples, when the player's converter isn't available.
+    converter: Converter,
+}
+
+/// Crossfades the audio between tracks, before it is written to the sink. On stop, the held
+/// back samples are faded out over `fade`.
+pub(crate) fn wrap(
+    sink: Box<dyn Sink>,
+    crossfade: Arc<Crossfade>,
+    fade: Duration,
+    ditherer: Option<DithererBuilder>,
+) -> Box<dyn Sink> {
+    let fade_len = ms_to_samples(fade.as_millis() as u64) as usize;
+    Box::new(CrossfadeSink {
+        sink,
+        crossfade,
+        fade_len: fade_len - fade_len % NUM_CHANNELS as usize,
+        converter: Converter::new(ditherer),
+    })
+}
+
+impl Sink for CrossfadeSink {
+    fn start(&mut self) -> SinkResult<()> {
+        self.sink.start()
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        // playing all of the held back samples would delay the pause by seconds
+        let mut held = self.crossfade.flush();
+        held.truncate(self.fade_len);
+        let frames = held.len() / NUM_CHANNELS as usize;
+        for (i, frame) in held.chunks_mut(NUM_CHANNELS as usize).enumerate() {
+            let gain = 1.0 - (i + 1) as f64 / frames as f64;
+            frame.iter_mut().for_each(|sample| *sample *= gain);
+        }
+        // the player exits if stopping fails, which a lost output must not cause
+        if !held.is_empty()
+            && let Err(err) = self
+                .sink
+                .write(AudioPacket::Samples(held), &mut self.converter)
+        {
+            warn!("Failed to write the faded out end of the track: {err}");
+        }
+        self.sink.stop()
+    }
+
+    fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        match packet {
+            AudioPacket::Samples(samples) => {
+                let samples = self.crossfade.process(samples);
+                if samples.is_empty() {
+                    return Ok(());
+                }
+                self.sink.write(AudioPacket::Samples(samples), converter)
+            }
+            // passthrough audio can't be mixed
+            raw => self.sink.write(raw, converter),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use librespot_core::SpotifyId;
+
+    #[test]
+    fn test_crossfade() {
+        let track = |id| SpotifyUri::Track {
+            id: SpotifyId::from_raw(&[id; 16]).unwrap(),
+        };
+        let crossfade = Crossfade::new(1);
+        let second = SAMPLES_PER_SECOND as usize;
+
+        // a track of 3 seconds, of which the last one is held back
+        crossfade.track_changed(track(1), 3000);
+        crossfade.position(&track(1), 0);
+        crossfade.arm(&track(1));
+        let played = crossfade.process(vec![1.0; 3 * second]);
+        assert_eq!(played.len(), 2 * second);
+
+        // and mixed into the next one
+        crossfade.track_changed(track(2), 3000);
+        crossfade.position(&track(2), 0);
+        let mixed = crossfade.process(vec![-1.0; second]);
+        assert_eq!(mixed.len(), second);
+        assert_eq!(mixed[0], 1.0);
+        let middle = mixed[second / 2];
+        assert!((middle - 0.0).abs() < 1e-3, "{middle}");
+        assert!((mixed[second - 1] + 1.0).abs() < 1e-3);
+
+        // the next track isn't armed, so it is played until its end
+        assert_eq!(
+            crossfade.process(vec![-1.0; 2 * second]),
+            vec![-1.0; 2 * second]
+        );
+
+        // pausing while the end of a track is held back drops it
+        crossfade.track_changed(track(3), 3000);
+        crossfade.position(&track(3), 0);
+        crossfade.arm(&track(3));
+        assert_eq!(crossfade.process(vec![0.5; 3 * second]).len(), 2 * second);
+        assert_eq!(crossfade.flush(), vec![0.5; second]);
+        assert!(crossfade.flush().is_empty());
+    }
+
+    struct TestSink(Arc<Mutex<Vec<f64>>>);
+
+    impl Sink for TestSink {
+        fn write(&mut self, packet: AudioPacket, _: &mut Converter) -> SinkResult<()> {
+            if let AudioPacket::Samples(samples) = packet {
+                self.0.lock().unwrap().extend(samples);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_stop() {
+        let track = SpotifyUri::Track {
+            id: SpotifyId::from_raw(&[1; 16]).unwrap(),
+        };
+        let crossfade = Arc::new(Crossfade::new(1));
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let fade = Duration::from_millis(100);
+        let mut sink = wrap(
+            Box::new(TestSink(written.clone())),
+            crossfade.clone(),
+            fade,
+            None,
+        );
+        let mut converter = Converter::new(None);
+        let second = SAMPLES_PER_SECOND as usize;
+
+        crossfade.track_changed(track.clone(), 3000);
+        crossfade.position(&track, 0);
+        crossfade.arm(&track);
+        sink.write(AudioPacket::Samples(vec![1.0; 3 * second]), &mut converter)
+            .unwrap();
+        assert_eq!(written.lock().unwrap().len(), 2 * second);
+
+        // only the length of a fade of the held back second is played, fading out
+        sink.stop().unwrap();
+        let written = written.lock().unwrap();
+        let fade_len = ms_to_samples(fade.as_millis() as u64) as usize;
+        assert_eq!(written.len(), 2 * second + fade_len);
+        assert!(written[2 * second] > 0.99);
+        assert_eq!(*written.last().unwrap(), 0.0);
+    }
+}
@@ -1,5 +1,18 @@
-use crate::config::DBusType;
+use crate::{
+    accounts::Accounts,
+    config::{CliConfig, DBusType, SleepArgs, TakeoverPolicy},
+    crossfade::{Crossfade, MAX_CROSSFADE_SECONDS},
+    duck::Ducker,
+    equalizer::Equalizer,
+    main_loop::{DeviceRegistry, MainLoopCommand, SleepTimer},
+    mute::MuteMixer,
+};
 use chrono::{Duration, prelude::*};
+use clap::ValueEnum as _;
+use color_eyre::{
+    Section as _,
+    eyre::{self, Context as _, eyre},
+};
 use dbus::{
     MethodErr,
     arg::{RefArg, Variant},
@@ -38,31 +51,61 @@ type DbusMap = HashMap<String, Variant<Box<dyn RefArg>>>;
 
 const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
 const CONTROLS_PATH: &str = "/rs/spotifyd/Controls";
+const CONTROLS_INTERFACE: &str = "rs.spotifyd.Controls";
+/// How long the sleep command waits for a reply of the daemon.
+const CLIENT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
 
 pub enum ControlMessage {
-    SetSession(Arc<Spirc>, Session),
+    SetSession(Arc<Spirc>, Session, Option<String>),
     DropSession,
     Shutdown,
 }
 
+/// The parts of the daemon that are exposed via the controls interface.
+pub(crate) struct DbusContext {
+    pub(crate) command_tx: UnboundedSender<MainLoopCommand>,
+    pub(crate) accounts: Option<Accounts>,
+    /// Distinguishes the D-Bus names of several devices run by the same process.
+    pub(crate) bus_name: Option<String>,
+    pub(crate) devices: DeviceRegistry,
+    /// The position of this device in `devices`.
+    pub(crate) device_index: usize,
+    pub(crate) device_id: String,
+    pub(crate) equalizer: Option<Arc<Equalizer>>,
+    /// The crossfade, if it has been enabled in the config.
+    pub(crate) crossfade: Option<Arc<Crossfade>>,
+    pub(crate) mute: Arc<MuteMixer>,
+    /// The ducker, which is there whenever D-Bus is.
+    pub(crate) ducker: Option<Arc<Ducker>>,
+}
+
 pub(crate) struct DbusServer {
     dbus_future: Pin<Box<dyn Future<Output = Result<(), DbusError>>>>,
     control_tx: UnboundedSender<ControlMessage>,
 }
 
 impl DbusServer {
-    pub fn new(event_rx: UnboundedReceiver<PlayerEvent>, dbus_type: DBusType) -> DbusServer {
+    pub fn new(
+        event_rx: UnboundedReceiver<PlayerEvent>,
+        dbus_type: DBusType,
+        context: DbusContext,
+    ) -> DbusServer {
         let (control_tx, control_rx) = tokio::sync::mpsc::unbounded_channel();
-        let dbus_future = Box::pin(create_dbus_server(event_rx, control_rx, dbus_type));
+        let dbus_future = Box::pin(create_dbus_server(event_rx, control_rx, dbus_type, context));
         DbusServer {
             dbus_future,
             control_tx,
         }
     }
 
-    pub fn set_session(&self, spirc: Arc<Spirc>, session: Session) -> Result<(), DbusError> {
+    pub fn set_session(
+        &self,
+        spirc: Arc<Spirc>,
+        session: Session,
+        account: Option<String>,
+    ) -> Result<(), DbusError> {
         self.control_tx
-            .send(ControlMessage::SetSession(spirc, session))
+            .send(ControlMessage::SetSession(spirc, session, account))
             .map_err(|_| DbusError::ControlChannelBroken)
     }
 
@@ -429,6 +472,7 @@ async fn create_dbus_server(
     mut event_rx: UnboundedReceiver<PlayerEvent>,
     mut control_rx: UnboundedReceiver<ControlMessage>,
     dbus_type: DBusType,
+    context: DbusContext,
 ) -> Result<(), DbusError> {
     let (resource, conn) = match dbus_type {
         DBusType::Session => connection::new_session_sync(),
@@ -436,13 +480,14 @@ async fn create_dbus_server(
     }?;
     let mut connection_task = tokio::spawn(async { Err::<(), _>(resource.await) });
 
+    let instance = match context.bus_name.as_deref() {
+        Some(bus_name) => format!("instance{}.{bus_name}", std::process::id()),
+        None => format!("instance{}", std::process::id()),
+    };
     // this name will be used, once we can provide the mpris interface
-    let mpris_name = format!(
-        "org.mpris.MediaPlayer2.spotifyd.instance{}",
-        std::process::id()
-    );
+    let mpris_name = format!("org.mpris.MediaPlayer2.spotifyd.{instance}");
     // this name will always be available to allow easy discovery of the controls interface
-    let spotifyd_name = format!("rs.spotifyd.instance{}", std::process::id());
+    let spotifyd_name = format!("rs.spotifyd.{instance}");
 
     conn.request_name(&spotifyd_name, false, true, true).await?;
 
@@ -488,6 +533,8 @@ async fn create_dbus_server(
         seeked_fn: SeekedSignal,
     }
     let mut cur_conn: Option<ConnectionData> = None;
+    // the account label and username of the last session, to announce when they change
+    let mut identity: Option<(String, String)> = None;
 
     loop {
         tokio::select! {
@@ -564,9 +611,36 @@ async fn create_dbus_server(
                     ControlMessage::Shutdown => {
                         break;
                     },
-                    ControlMessage::SetSession(new_spirc, new_session) => {
+                    ControlMessage::SetSession(new_spirc, new_session, account) => {
+                        let mut changed = DbusMap::new();
+                        let new_identity = (account.clone().unwrap_or_default(), new_session.username());
+                        if let Some((old_account, old_username)) = &identity {
+                            if *old_account != new_identity.0 {
+                                insert_attr(&mut changed, "Account", new_identity.0.clone());
+                            }
+                            if *old_username != new_identity.1 {
+                                insert_attr(&mut changed, "Username", new_identity.1.clone());
+                            }
+                        }
+                        identity = Some(new_identity);
+
                         let mut cr = crossroads.lock().await;
-                        register_controls_interface(&mut cr, new_spirc.clone());
+                        register_controls_interface(
+                            &mut cr,
+                            new_spirc.clone(),
+                            new_session.clone(),
+                            account,
+                            &context,
+                        );
+                        if !changed.is_empty() {
+                            let msg = PropertiesPropertiesChanged {
+                                interface_name: CONTROLS_INTERFACE.to_owned(),
+                                changed_properties: changed,
+                                invalidated_properties: Vec::new(),
+                            };
+                            conn.send(msg.to_emit_message(&dbus::Path::new(CONTROLS_PATH).unwrap()))
+                                .unwrap();
+                        }
                         spirc = Some(new_spirc);
                         session = Some(new_session);
                     }
@@ -898,8 +972,18 @@ fn register_player_interface(
     seeked_signal.expect("player interface has not been registered")
 }
 
-fn register_controls_interface(cr: &mut Crossroads, spirc: Arc<Spirc>) {
-    let spotifyd_ctrls_interface: IfaceToken<()> = cr.register("rs.spotifyd.Controls", |b| {
+fn ducking_disabled() -> MethodErr {
+    MethodErr::failed("ducking is not enabled")
+}
+
+fn register_controls_interface(
+    cr: &mut Crossroads,
+    spirc: Arc<Spirc>,
+    session: Session,
+    account: Option<String>,
+    ctx: &DbusContext,
+) {
+    let spotifyd_ctrls_interface: IfaceToken<()> = cr.register(CONTROLS_INTERFACE, |b| {
         let local_spirc = spirc.clone();
         b.method("VolumeUp", (), (), move |_, _, (): ()| {
             local_spirc.volume_up().map_err(|e| MethodErr::failed(&e))
@@ -909,15 +993,404 @@ fn register_controls_interface(cr: &mut Crossroads, spirc: Arc<Spirc>) {
             local_spirc.volume_down().map_err(|e| MethodErr::failed(&e))
         });
 
+        let local_spirc = spirc.clone();
+        let local_mute = ctx.mute.clone();
+        b.method("Mute", (), (), move |_, _, (): ()| {
+            local_mute
+                .set_muted(true, Some(&local_spirc))
+                .map_err(|e| MethodErr::failed(&e))
+        });
+        let local_spirc = spirc.clone();
+        let local_mute = ctx.mute.clone();
+        b.method("Unmute", (), (), move |_, _, (): ()| {
+            local_mute
+                .set_muted(false, Some(&local_spirc))
+                .map_err(|e| MethodErr::failed(&e))
+        });
+        let local_spirc = spirc.clone();
+        let local_mute = ctx.mute.clone();
+        b.method("ToggleMute", (), (), move |_, _, (): ()| {
+            local_mute
+                .set_muted(!local_mute.is_muted(), Some(&local_spirc))
+                .map_err(|e| MethodErr::failed(&e))
+        });
+        let local_mute = ctx.mute.clone();
+        b.property("Muted")
+            .emits_changed_false()
+            .get(move |_, _| Ok(local_mute.is_muted()));
+
+        let local_ducker = ctx.ducker.clone();
+        b.method(
+            "Duck",
+            ("db", "seconds"),
+            (),
+            move |_, _, (db, seconds): (f64, u32)| {
+                if !db.is_finite() {
+                    return Err(MethodErr::invalid_arg(&db));
+                }
+                // zero seconds ducks until Unduck is called
+                let duration =
+                    (seconds > 0).then(|| std::time::Duration::from_secs(seconds.into()));
+                let ducker = local_ducker.as_ref().ok_or_else(ducking_disabled)?;
+                ducker.duck(db, duration);
+                Ok(())
+            },
+        );
+        let local_ducker = ctx.ducker.clone();
+        b.method("Unduck", (), (), move |_, _, (): ()| {
+            local_ducker.as_ref().ok_or_else(ducking_disabled)?.unduck();
+            Ok(())
+        });
+        let local_ducker = ctx.ducker.clone();
+        b.property("DuckedDb")
+            .emits_changed_false()
+            .get(move |_, _| Ok(local_ducker.as_ref().map_or(0.0, |ducker| ducker.ducked_db())));
+
         let local_spirc = spirc.clone();
         b.method("TransferPlayback", (), (), move |_, _, (): ()| {
             local_spirc.activate().map_err(|e| MethodErr::failed(&e))
         });
+
+        let local_accounts = ctx.accounts.clone();
+        let local_command_tx = ctx.command_tx.clone();
+        b.method(
+            "SwitchAccount",
+            ("account",),
+            (),
+            move |_, _, (account,): (String,)| {
+                // the default account is the one without a label
+                let label = (!account.is_empty()).then_some(account);
+                let known = local_accounts.as_ref().is_some_and(|accounts| match &label {
+                    Some(label) => accounts.list().contains(label),
+                    None => accounts.credentials(None).is_some(),
+                });
+                if !known {
+                    return Err(MethodErr::invalid_arg(&format!(
+                        "no credentials stored for account '{}'",
+                        label.as_deref().unwrap_or("default")
+                    )));
+                }
+                local_command_tx
+                    .send(MainLoopCommand::SwitchAccount(label))
+                    .map_err(|_| MethodErr::failed("spotifyd is shutting down"))
+            },
+        );
+
+        let local_devices = ctx.devices.clone();
+        let device_index = ctx.device_index;
+        let local_command_tx = ctx.command_tx.clone();
+        b.method("ForceTakeover", (), (), move |_, _, (): ()| {
+            let devices = local_devices.read().map_err(|_| StatePoisonError)?;
+            if devices[device_index].pending_takeover.is_none() {
+                return Err(MethodErr::failed("there is no pending discovery login"));
+            }
+            local_command_tx
+                .send(MainLoopCommand::ForceTakeover)
+                .map_err(|_| MethodErr::failed("spotifyd is shutting down"))
+        });
+
+        let local_devices = ctx.devices.clone();
+        let local_command_tx = ctx.command_tx.clone();
+        b.property("TakeoverPolicy")
+            .emits_changed_false()
+            .get(move |_, _| {
+                let devices = local_devices.read().map_err(|_| StatePoisonError)?;
+                let policy = devices[device_index].takeover_policy;
+                Ok(policy
+                    .to_possible_value()
+                    .expect("no policy is skipped")
+                    .get_name()
+                    .to_string())
+            })
+            .set(move |_, _, value: String| {
+                let policy = TakeoverPolicy::from_str(&value, true).map_err(|_| {
+                    MethodErr::invalid_arg(&format!("unknown takeover policy '{value}'"))
+                })?;
+                local_command_tx
+                    .send(MainLoopCommand::SetTakeoverPolicy(policy))
+                    .map_err(|_| MethodErr::failed("spotifyd is shutting down"))?;
+                Ok(None)
+            });
+        let local_devices = ctx.devices.clone();
+        b.property("PendingTakeover")
+            .emits_changed_false()
+            .get(move |_, _| {
+                let devices = local_devices.read().map_err(|_| StatePoisonError)?;
+                Ok(devices[device_index]
+                    .pending_takeover
+                    .clone()
+                    .unwrap_or_default())
+            });
+
+        let local_command_tx = ctx.command_tx.clone();
+        b.method(
+            "SetSleepTimer",
+            ("seconds",),
+            (),
+            move |_, _, (seconds,): (u32,)| {
+                if seconds == 0 {
+                    return Err(MethodErr::invalid_arg(&seconds));
+                }
+                let at = std::time::Instant::now() + std::time::Duration::from_secs(seconds.into());
+                local_command_tx
+                    .send(MainLoopCommand::SetSleepTimer(Some(SleepTimer::At(at))))
+                    .map_err(|_| MethodErr::failed("spotifyd is shutting down"))
+            },
+        );
+        let local_command_tx = ctx.command_tx.clone();
+        b.method("SetSleepTimerEndOfTrack", (), (), move |_, _, (): ()| {
+            local_command_tx
+                .send(MainLoopCommand::SetSleepTimer(Some(SleepTimer::EndOfTrack)))
+                .map_err(|_| MethodErr::failed("spotifyd is shutting down"))
+        });
+        let local_command_tx = ctx.command_tx.clone();
+        b.method("CancelSleepTimer", (), (), move |_, _, (): ()| {
+            local_command_tx
+                .send(MainLoopCommand::SetSleepTimer(None))
+                .map_err(|_| MethodErr::failed("spotifyd is shutting down"))
+        });
+        let local_devices = ctx.devices.clone();
+        b.property("SleepTimerRemaining")
+            .emits_changed_false()
+            .get(move |_, _| {
+                let devices = local_devices.read().map_err(|_| StatePoisonError)?;
+                Ok(devices[device_index]
+                    .sleep_timer
+                    .and_then(|timer| timer.remaining())
+                    .map_or(0, |remaining| remaining.as_secs()))
+            });
+        let local_devices = ctx.devices.clone();
+        b.property("SleepTimerEndOfTrack")
+            .emits_changed_false()
+            .get(move |_, _| {
+                let devices = local_devices.read().map_err(|_| StatePoisonError)?;
+                Ok(devices[device_index].sleep_timer == Some(SleepTimer::EndOfTrack))
+            });
+
+        let local_command_tx = ctx.command_tx.clone();
+        b.method("Rename", ("name",), (), move |_, _, (name,): (String,)| {
+            if name.trim().is_empty() {
+                return Err(MethodErr::invalid_arg("the name must not be empty"));
+            }
+            local_command_tx
+                .send(MainLoopCommand::Rename(name))
+                .map_err(|_| MethodErr::failed("spotifyd is shutting down"))
+        });
+        let local_devices = ctx.devices.clone();
+        b.property("DeviceName")
+            .emits_changed_false()
+            .get(move |_, _| {
+                let devices = local_devices.read().map_err(|_| StatePoisonError)?;
+                Ok(devices[device_index].name.clone())
+            });
+        let local_devices = ctx.devices.clone();
+        let local_command_tx = ctx.command_tx.clone();
+        b.property("IsGroup")
+            .emits_changed_false()
+            .get(move |_, _| {
+                let devices = local_devices.read().map_err(|_| StatePoisonError)?;
+                Ok(devices[device_index].is_group)
+            })
+            .set(move |_, _, is_group: bool| {
+                local_command_tx
+                    .send(MainLoopCommand::SetGroup(is_group))
+                    .map_err(|_| MethodErr::failed("spotifyd is shutting down"))?;
+                Ok(None)
+            });
+        let device_id = ctx.device_id.clone();
+        b.property("DeviceId")
+            .emits_changed_const()
+            .get(move |_, _| Ok(device_id.clone()));
+
+        let local_equalizer = ctx.equalizer.clone();
+        b.property("EqualizerPresets")
+            .emits_changed_const()
+            .get(move |_, _| {
+                Ok(local_equalizer
+                    .as_ref()
+                    .map(|equalizer| equalizer.presets())
+                    .unwrap_or_default())
+            });
+        let local_equalizer = ctx.equalizer.clone();
+        let local_equalizer_set = ctx.equalizer.clone();
+        b.property("EqualizerPreset")
+            .emits_changed_false()
+            .get(move |_, _| {
+                Ok(local_equalizer
+                    .as_ref()
+                    .and_then(|equalizer| equalizer.preset())
+                    .unwrap_or_default())
+            })
+            .set(move |_, _, name: String| {
+                let equalizer = local_equalizer_set
+                    .as_ref()
+                    .ok_or_else(|| MethodErr::failed("no equalizer presets are configured"))?;
+                let name = Some(name).filter(|name| !name.is_empty());
+                equalizer
+                    .set_preset(name.as_deref())
+                    .map_err(|err| MethodErr::invalid_arg(&err))?;
+                Ok(None)
+            });
+
+        let local_crossfade = ctx.crossfade.clone();
+        let local_crossfade_set = ctx.crossfade.clone();
+        b.property("CrossfadeSeconds")
+            .emits_changed_false()
+            .get(move |_, _| Ok(local_crossfade.as_ref().map_or(0, |crossfade| crossfade.seconds()) as u32))
+            .set(move |_, _, seconds: u32| {
+                let seconds = u8::try_from(seconds)
+                    .ok()
+                    .filter(|seconds| *seconds <= MAX_CROSSFADE_SECONDS)
+                    .ok_or_else(|| {
+                        MethodErr::invalid_arg(&format!(
+                            "the crossfade must be between 0 and {MAX_CROSSFADE_SECONDS} seconds"
+                        ))
+                    })?;
+                match &local_crossfade_set {
+                    Some(crossfade) => crossfade.set_seconds(seconds),
+                    None if seconds == 0 => (),
+                    None => {
+                        return Err(MethodErr::failed(
+                            "the crossfade can only be changed if crossfade_seconds is set in the config",
+                        ));
+                    }
+                }
+                Ok(None)
+            });
+
+        let account = account.clone().unwrap_or_default();
+        b.property("Account")
+            .emits_changed_true()
+            .get(move |_, _| Ok(account.clone()));
+        let local_accounts = ctx.accounts.clone();
+        b.property("Accounts")
+            .emits_changed_false()
+            .get(move |_, _| {
+                Ok(local_accounts
+                    .as_ref()
+                    .map(Accounts::list)
+                    .unwrap_or_default())
+            });
+        let session = session.clone();
+        b.property("Username")
+            .emits_changed_true()
+            .get(move |_, _| Ok(session.username()));
+        let devices = ctx.devices.clone();
+        b.property("Devices")
+            .emits_changed_false()
+            .get(move |_, _| {
+                let devices = devices.read().map_err(|_| StatePoisonError)?;
+                Ok(devices
+                    .iter()
+                    .map(|device| {
+                        (
+                            device.name.clone(),
+                            device.bus_name.clone().unwrap_or_default(),
+                            device.username.clone().unwrap_or_default(),
+                            device.playing,
+                            device.is_group,
+                        )
+                    })
+                    .collect::<Vec<_>>())
+            });
     });
 
     cr.insert(CONTROLS_PATH, &[spotifyd_ctrls_interface], ());
 }
 
+/// Sets, cancels or shows the sleep timer of a running spotifyd.
+pub(crate) fn run_sleep_command(mut cli_config: CliConfig, args: SleepArgs) -> eyre::Result<()> {
+    cli_config
+        .load_config_file_values()
+        .wrap_err("failed to read config file")?;
+    let conn = match cli_config.shared_config.dbus_type() {
+        DBusType::Session => dbus::blocking::Connection::new_session(),
+        DBusType::System => dbus::blocking::Connection::new_system(),
+    }
+    .wrap_err("failed to connect to D-Bus")?;
+    let dest = find_controls(&conn, args.bus_name.as_deref())?;
+    let controls = conn.with_proxy(dest, CONTROLS_PATH, CLIENT_TIMEOUT);
+
+    let call = |method: &str, result: Result<(), dbus::Error>| {
+        result.wrap_err_with(|| format!("failed to call {method}"))
+    };
+    if args.cancel {
+        call(
+            "CancelSleepTimer",
+            controls.method_call(CONTROLS_INTERFACE, "CancelSleepTimer", ()),
+        )?;
+        println!("Cancelled the sleep timer");
+    } else if args.end_of_track {
+        call(
+            "SetSleepTimerEndOfTrack",
+            controls.method_call(CONTROLS_INTERFACE, "SetSleepTimerEndOfTrack", ()),
+        )?;
+        println!("Pausing playback after the current track");
+    } else if let Some(minutes) = args.minutes {
+        let seconds = minutes.saturating_mul(60);
+        call(
+            "SetSleepTimer",
+            controls.method_call(CONTROLS_INTERFACE, "SetSleepTimer", (seconds,)),
+        )?;
+        println!("Pausing playback in {minutes} minutes");
+    } else {
+        use dbus::blocking::stdintf::org_freedesktop_dbus::Properties as _;
+
+        let end_of_track: bool = controls
+            .get(CONTROLS_INTERFACE, "SleepTimerEndOfTrack")
+            .wrap_err("failed to read the sleep timer")?;
+        let remaining: u64 = controls
+            .get(CONTROLS_INTERFACE, "SleepTimerRemaining")
+            .wrap_err("failed to read the sleep timer")?;
+        if end_of_track {
+            println!("Pausing playback after the current track");
+        } else if remaining > 0 {
+            println!(
+                "Pausing playback in {}:{:02} minutes",
+                remaining / 60,
+                remaining % 60
+            );
+        } else {
+            println!("The sleep timer is off");
+        }
+    }
+    Ok(())
+}
+
+/// The D-Bus name of the controls of the running device with the given `bus_name`.
+fn find_controls(
+    conn: &dbus::blocking::Connection,
+    bus_name: Option<&str>,
+) -> eyre::Result<String> {
+    let (names,): (Vec<String>,) = conn
+        .with_proxy("org.freedesktop.DBus", "/", CLIENT_TIMEOUT)
+        .method_call("org.freedesktop.DBus", "ListNames", ())
+        .wrap_err("failed to list the D-Bus names")?;
+    let mut found: Vec<String> = names
+        .into_iter()
+        .filter(|name| {
+            // rs.spotifyd.instance$PID, followed by the bus name of the device, if any
+            let Some(instance) = name.strip_prefix("rs.spotifyd.instance") else {
+                return false;
+            };
+            bus_name.is_none_or(|bus_name| {
+                instance.split_once('.').map(|(_, suffix)| suffix) == Some(bus_name)
+            })
+        })
+        .collect();
+    match found.len() {
+        1 => Ok(found.remove(0)),
+        0 => Err(eyre!("no running spotifyd has been found on D-Bus")).with_suggestion(
+            || "check that it is connected to Spotify, and use the same dbus_type as the daemon",
+        ),
+        _ => Err(eyre!(
+            "several spotifyd devices are running: {}",
+            found.join(", ")
+        ))
+        .with_suggestion(|| "choose one with --bus-name"),
+    }
+}
+
 fn uri_to_object_path(uri: Option<&str>) -> dbus::Path<'static> {
     let Some(uri) = uri else {
         return dbus::Path::new("/org/mpris/MediaPlayer2/TrackList/NoTrack").unwrap();
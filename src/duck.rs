@@ -0,0 +1,239 @@
+//! Ducking, i.e. lowering the audio for a while, e.g. during announcements on the same amplifier.
+//!
+//! The audio is lowered by the duck sink, after the volume has been applied, so the volume shown
+//! in Spotify stays the same. The gain changes gradually, to avoid clicks.
+//!
+//! The fade-out of the sleep timer is applied by the same sink, but independently of the ducking,
+//! so that ducking during the fade-out doesn't interrupt it and the end of the fade-out doesn't
+//! end the ducking.
+
+use librespot_playback::{
+    NUM_CHANNELS, SAMPLE_RATE,
+    audio_backend::{Sink, SinkResult},
+    convert::Converter,
+    decoder::AudioPacket,
+};
+use log::info;
+use std::{
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// How long it takes to duck by the full range, or to return from it.
+const RAMP: Duration = Duration::from_millis(500);
+
+#[derive(Default)]
+struct State {
+    /// The attenuation in dB, zero if not ducked.
+    db: f64,
+    /// When to return to the normal volume, if not only on `unduck`.
+    until: Option<Instant>,
+    /// How long fading out to silence takes, `None` if not faded out.
+    fade: Option<Duration>,
+}
+
+/// Lowers the audio of the duck sinks.
+#[derive(Default)]
+pub(crate) struct Ducker {
+    state: Mutex<State>,
+}
+
+impl Ducker {
+    fn state(&self) -> MutexGuard<'_, State> {
+        let mut state = self.state.lock().expect("ducker has been poisoned");
+        if state.until.is_some_and(|until| until <= Instant::now()) {
+            info!("Unducking, since the duration has passed");
+            state.db = 0.0;
+            state.until = None;
+        }
+        state
+    }
+
+    /// Lowers the audio by `db`, for `duration` or until [`Ducker::unduck`] is called.
+    #[cfg_attr(not(any(feature = "dbus_mpris", unix)), expect(dead_code))]
+    pub(crate) fn duck(&self, db: f64, duration: Option<Duration>) {
+        let db = db.abs();
+        match duration {
+            Some(duration) => info!("Ducking by {db} dB for {duration:?}"),
+            None => info!("Ducking by {db} dB"),
+        }
+        let mut state = self.state();
+        state.db = db;
+        state.until = duration.map(|duration| Instant::now() + duration);
+    }
+
+    #[cfg_attr(not(any(feature = "dbus_mpris", unix)), expect(dead_code))]
+    pub(crate) fn unduck(&self) {
+        let mut state = self.state();
+        if state.db != 0.0 {
+            info!("Unducking");
+        }
+        state.db = 0.0;
+        state.until = None;
+    }
+
+    /// Lowers the audio to silence over `duration`, until [`Ducker::fade_in`] is called.
+    pub(crate) fn fade_out(&self, duration: Duration) {
+        info!("Fading out over {duration:?}");
+        self.state().fade = Some(duration);
+    }
+
+    /// Restores the audio after [`Ducker::fade_out`], keeping any ducking.
+    pub(crate) fn fade_in(&self) {
+        if self.state().fade.take().is_some() {
+            info!("Fading in again");
+        }
+    }
+
+    /// The current attenuation in dB, zero if not ducked.
+    #[cfg_attr(not(feature = "dbus_mpris"), expect(dead_code))]
+    pub(crate) fn ducked_db(&self) -> f64 {
+        self.state().db
+    }
+
+    /// The gains of the ducking and the fade-out to move towards, and how long that takes from
+    /// the full range.
+    fn gains(&self) -> [(f64, Duration); 2] {
+        let state = self.state();
+        let fade = match state.fade {
+            Some(duration) => (0.0, duration),
+            None => (1.0, RAMP),
+        };
+        [(10f64.powf(-state.db / 20.0), RAMP), fade]
+    }
+}
+
+/// Ducks by `db` on `SIGUSR1`, for `duration` or until `SIGUSR2`.
+#[cfg(unix)]
+pub(crate) fn handle_signals(
+    ducker: Arc<Ducker>,
+    db: f64,
+    duration: Option<Duration>,
+) -> std::io::Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut duck = signal(SignalKind::user_defined1())?;
+    let mut unduck = signal(SignalKind::user_defined2())?;
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(()) = duck.recv() => ducker.duck(db, duration),
+                Some(()) = unduck.recv() => ducker.unduck(),
+                else => break,
+            }
+        }
+    });
+    Ok(())
+}
+
+struct DuckSink {
+    sink: Box<dyn Sink>,
+    ducker: Arc<Ducker>,
+    /// The current gains of the ducking and the fade-out.
+    gains: [f64; 2],
+}
+
+/// Lowers the audio by the gain of the ducker, before it is written to the sink.
+pub(crate) fn wrap(sink: Box<dyn Sink>, ducker: Arc<Ducker>) -> Box<dyn Sink> {
+    Box::new(DuckSink {
+        sink,
+        ducker,
+        gains: [1.0; 2],
+    })
+}
+
+impl Sink for DuckSink {
+    fn start(&mut self) -> SinkResult<()> {
+        self.sink.start()
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        self.sink.stop()
+    }
+
+    fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        let mut samples = match packet {
+            AudioPacket::Samples(samples) => samples,
+            raw => return self.sink.write(raw, converter),
+        };
+        let targets = self.ducker.gains();
+        if self.gains == [1.0; 2] && targets.iter().all(|&(target, _)| target == 1.0) {
+            return self.sink.write(AudioPacket::Samples(samples), converter);
+        }
+        // the change of each gain per frame
+        let steps = targets.map(|(_, ramp)| {
+            let ramp_frames = ramp.as_micros() * SAMPLE_RATE as u128 / 1_000_000;
+            1.0 / ramp_frames.max(1) as f64
+        });
+        for frame in samples.chunks_mut(NUM_CHANNELS as usize) {
+            for ((gain, (target, _)), step) in self.gains.iter_mut().zip(targets).zip(steps) {
+                if *gain != target {
+                    *gain = if *gain < target {
+                        (*gain + step).min(target)
+                    } else {
+                        (*gain - step).max(target)
+                    };
+                }
+            }
+            let gain = self.gains[0] * self.gains[1];
+            frame.iter_mut().for_each(|sample| *sample *= gain);
+        }
+        self.sink.write(AudioPacket::Samples(samples), converter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestSink(Arc<Mutex<Vec<f64>>>);
+
+    impl Sink for TestSink {
+        fn write(&mut self, packet: AudioPacket, _: &mut Converter) -> SinkResult<()> {
+            if let AudioPacket::Samples(samples) = packet {
+                self.0.lock().unwrap().extend(samples);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_ducking() {
+        let ducker = Arc::new(Ducker::default());
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let mut sink = wrap(Box::new(TestSink(written.clone())), ducker.clone());
+        let mut converter = Converter::new(None);
+        let ramp_len = (RAMP.as_millis() as usize * SAMPLE_RATE as usize / 1000) * 2;
+        let mut write = |sink: &mut Box<dyn Sink>| {
+            sink.write(AudioPacket::Samples(vec![1.0; ramp_len]), &mut converter)
+                .unwrap();
+            *written.lock().unwrap().last().unwrap()
+        };
+
+        assert_eq!(write(&mut sink), 1.0);
+        ducker.duck(20.0, None);
+        // the gain is lowered gradually
+        let ramped = written.lock().unwrap().len();
+        assert!((write(&mut sink) - 0.1).abs() < 1e-9);
+        assert!(written.lock().unwrap()[ramped + ramp_len / 8] > 0.5);
+
+        ducker.unduck();
+        assert_eq!(write(&mut sink), 1.0);
+
+        // the duration has already passed
+        ducker.duck(6.0, Some(Duration::ZERO));
+        assert_eq!(write(&mut sink), 1.0);
+
+        // fading out takes as long as requested, and ducking doesn't interrupt it
+        ducker.fade_out(RAMP * 4);
+        assert!((write(&mut sink) - 0.75).abs() < 1e-6);
+        ducker.duck(20.0, None);
+        assert!((write(&mut sink) - 0.05).abs() < 1e-6);
+        write(&mut sink);
+        assert_eq!(write(&mut sink), 0.0);
+
+        // fading in keeps the ducking
+        ducker.fade_in();
+        assert!((write(&mut sink) - 0.1).abs() < 1e-9);
+    }
+}
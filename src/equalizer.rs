@@ -0,0 +1,381 @@
+//! A parametric equalizer, which filters the audio between the player and the sink.
+//!
+//! A preset consists of a preamp and biquad filters, designed after the formulae of the
+//! "Audio EQ Cookbook" by Robert Bristow-Johnson.
+
+use crate::config::{Channel, EqualizerPreset, FilterConfig, FilterType};
+use color_eyre::{
+    Section,
+    eyre::{self, eyre},
+};
+use librespot_playback::{
+    NUM_CHANNELS, SAMPLE_RATE,
+    audio_backend::{Sink, SinkResult},
+    convert::Converter,
+    decoder::AudioPacket,
+};
+use log::info;
+use std::{
+    collections::BTreeMap,
+    f64::consts::TAU,
+    sync::{Arc, Mutex},
+};
+
+/// A biquad filter in transposed direct form II.
+struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    z1: f64,
+    z2: f64,
+}
+
+impl Biquad {
+    fn new(filter: &FilterConfig, sample_rate: f64) -> Self {
+        let a = 10f64.powf(filter.gain_db / 40.0);
+        let w0 = TAU * filter.freq / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * filter.q);
+        let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+
+        let (b, a) = match filter.kind {
+            FilterType::Peaking => (
+                [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+                [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+            ),
+            FilterType::LowShelf => (
+                [
+                    a * ((a + 1.0) - (a - 1.0) * cos + sqrt_a_alpha),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - sqrt_a_alpha),
+                ],
+                [
+                    (a + 1.0) + (a - 1.0) * cos + sqrt_a_alpha,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                    (a + 1.0) + (a - 1.0) * cos - sqrt_a_alpha,
+                ],
+            ),
+            FilterType::HighShelf => (
+                [
+                    a * ((a + 1.0) + (a - 1.0) * cos + sqrt_a_alpha),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                    a * ((a + 1.0) + (a - 1.0) * cos - sqrt_a_alpha),
+                ],
+                [
+                    (a + 1.0) - (a - 1.0) * cos + sqrt_a_alpha,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                    (a + 1.0) - (a - 1.0) * cos - sqrt_a_alpha,
+                ],
+            ),
+            FilterType::LowPass => (
+                [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+            FilterType::HighPass => (
+                [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+        };
+
+        Self {
+            b0: b[0] / a[0],
+            b1: b[1] / a[0],
+            b2: b[2] / a[0],
+            a1: a[1] / a[0],
+            a2: a[2] / a[0],
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+/// The filters of a preset, with their state for every channel.
+struct FilterChain {
+    preamp: f64,
+    channels: Vec<Vec<Biquad>>,
+}
+
+impl FilterChain {
+    fn new(preset: &EqualizerPreset) -> Self {
+        let channels = (0..NUM_CHANNELS as usize)
+            .map(|channel| {
+                preset
+                    .filters
+                    .iter()
+                    .filter(|filter| {
+                        filter.channel.is_none_or(|only| {
+                            channel
+                                == match only {
+                                    Channel::Left => 0,
+                                    Channel::Right => 1,
+                                }
+                        })
+                    })
+                    .map(|filter| Biquad::new(filter, SAMPLE_RATE as f64))
+                    .collect()
+            })
+            .collect();
+        Self {
+            preamp: 10f64.powf(preset.preamp_db / 20.0),
+            channels,
+        }
+    }
+
+    fn process(&mut self, samples: &mut [f64]) {
+        for frame in samples.chunks_exact_mut(self.channels.len()) {
+            for (sample, filters) in frame.iter_mut().zip(&mut self.channels) {
+                *sample = filters
+                    .iter_mut()
+                    .fold(*sample * self.preamp, |x, filter| filter.process(x));
+            }
+        }
+    }
+}
+
+fn validate(name: &str, preset: &EqualizerPreset) -> eyre::Result<()> {
+    let nyquist = SAMPLE_RATE as f64 / 2.0;
+    for filter in &preset.filters {
+        if !(filter.freq > 0.0 && filter.freq < nyquist) {
+            return Err(eyre!(
+                "the frequency {} of a filter in the equalizer preset '{name}' is out of range",
+                filter.freq
+            )
+            .with_suggestion(|| format!("use a frequency between 0 and {nyquist} Hz")));
+        }
+        if !(filter.q > 0.0 && filter.q.is_finite() && filter.gain_db.is_finite()) {
+            return Err(eyre!(
+                "a filter in the equalizer preset '{name}' has an invalid q or gain_db"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The equalizer presets, of which one (or none) is active.
+pub(crate) struct Equalizer {
+    presets: BTreeMap<String, EqualizerPreset>,
+    active: Mutex<Option<(String, FilterChain)>>,
+}
+
+impl Equalizer {
+    pub(crate) fn new(
+        presets: BTreeMap<String, EqualizerPreset>,
+        active: Option<&str>,
+    ) -> eyre::Result<Self> {
+        for (name, preset) in &presets {
+            validate(name, preset)?;
+        }
+        let equalizer = Self {
+            presets,
+            active: Mutex::new(None),
+        };
+        if let Some(name) = active {
+            equalizer.set_preset(Some(name)).map_err(|err| {
+                err.with_suggestion(|| {
+                    format!("available presets: {}", equalizer.presets().join(", "))
+                })
+            })?;
+        }
+        Ok(equalizer)
+    }
+
+    pub(crate) fn presets(&self) -> Vec<String> {
+        self.presets.keys().cloned().collect()
+    }
+
+    /// The name of the active preset.
+    #[cfg_attr(not(feature = "dbus_mpris"), expect(dead_code))]
+    pub(crate) fn preset(&self) -> Option<String> {
+        let active = self.active.lock().expect("equalizer has been poisoned");
+        active.as_ref().map(|(name, _)| name.clone())
+    }
+
+    /// Activates a preset, or turns the equalizer off.
+    pub(crate) fn set_preset(&self, name: Option<&str>) -> eyre::Result<()> {
+        let chain = match name {
+            Some(name) => {
+                let preset = self
+                    .presets
+                    .get(name)
+                    .ok_or_else(|| eyre!("unknown equalizer preset '{name}'"))?;
+                info!("Using the equalizer preset '{name}'");
+                Some((name.to_string(), FilterChain::new(preset)))
+            }
+            None => {
+                info!("Turning the equalizer off");
+                None
+            }
+        };
+        *self.active.lock().expect("equalizer has been poisoned") = chain;
+        Ok(())
+    }
+
+    fn process(&self, samples: &mut [f64]) {
+        let mut active = self.active.lock().expect("equalizer has been poisoned");
+        if let Some((_, chain)) = active.as_mut() {
+            chain.process(samples);
+        }
+    }
+}
+
+struct EqualizerSink {
+    sink: Box<dyn Sink>,
+    equalizer: Arc<Equalizer>,
+}
+
+/// Filters the audio through the equalizer, before it is written to the sink.
+pub(crate) fn wrap(sink: Box<dyn Sink>, equalizer: Arc<Equalizer>) -> Box<dyn Sink> {
+    Box::new(EqualizerSink { sink, equalizer })
+}
+
+impl Sink for EqualizerSink {
+    fn start(&mut self) -> SinkResult<()> {
+        self.sink.start()
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        self.sink.stop()
+    }
+
+    fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        let packet = match packet {
+            AudioPacket::Samples(mut samples) => {
+                self.equalizer.process(&mut samples);
+                AudioPacket::Samples(samples)
+            }
+            raw => raw,
+        };
+        self.sink.write(packet, converter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::FRAC_1_SQRT_2;
+
+    /// Reference impulse responses, computed with the cookbook formulae at 44.1 kHz.
+    const REFERENCES: [(FilterType, f64, f64, f64, [f64; 6]); 5] = [
+        (
+            FilterType::Peaking,
+            1000.0,
+            1.0,
+            6.0,
+            [
+                1.047630026200,
+                0.089782182742,
+                0.078537413820,
+                0.066853523404,
+                0.054997984761,
+                0.043215982347,
+            ],
+        ),
+        (
+            FilterType::LowPass,
+            1000.0,
+            FRAC_1_SQRT_2,
+            0.0,
+            [
+                0.004603998475,
+                0.017491034076,
+                0.032308229220,
+                0.043826481882,
+                0.052435688076,
+                0.058508165610,
+            ],
+        ),
+        (
+            FilterType::HighPass,
+            100.0,
+            FRAC_1_SQRT_2,
+            0.0,
+            [
+                0.989976012680,
+                -0.019946489603,
+                -0.019743549820,
+                -0.019540690400,
+                -0.019337950509,
+                -0.019135368505,
+            ],
+        ),
+        (
+            FilterType::LowShelf,
+            200.0,
+            FRAC_1_SQRT_2,
+            -4.0,
+            [
+                0.995361652782,
+                -0.009251437167,
+                -0.009197428408,
+                -0.009136614413,
+                -0.009069356810,
+                -0.008996007679,
+            ],
+        ),
+        (
+            FilterType::HighShelf,
+            6000.0,
+            FRAC_1_SQRT_2,
+            3.0,
+            [
+                1.279605485394,
+                -0.207178119455,
+                -0.094522427340,
+                -0.015216528515,
+                0.014634988270,
+                0.015565037355,
+            ],
+        ),
+    ];
+
+    #[test]
+    fn test_impulse_responses() {
+        for (kind, freq, q, gain_db, reference) in REFERENCES {
+            let filter = FilterConfig {
+                kind,
+                freq,
+                q,
+                gain_db,
+                channel: None,
+            };
+            let mut biquad = Biquad::new(&filter, 44100.0);
+            for (i, expected) in reference.into_iter().enumerate() {
+                let y = biquad.process(if i == 0 { 1.0 } else { 0.0 });
+                assert!(
+                    (y - expected).abs() < 1e-9,
+                    "{kind:?} tap {i}: {y} != {expected}"
+                );
+            }
+        }
+
+        // a filter for a single channel leaves the other one alone, the preamp applies to both
+        let preset = EqualizerPreset {
+            preamp_db: -6.0,
+            filters: vec![FilterConfig {
+                kind: FilterType::LowPass,
+                freq: 1000.0,
+                q: FRAC_1_SQRT_2,
+                gain_db: 0.0,
+                channel: Some(Channel::Right),
+            }],
+        };
+        let equalizer =
+            Equalizer::new(BTreeMap::from([("test".to_string(), preset)]), Some("test")).unwrap();
+        let mut samples = vec![1.0, 1.0, 0.0, 0.0];
+        equalizer.process(&mut samples);
+        let preamp = 10f64.powf(-6.0 / 20.0);
+        assert!((samples[0] - preamp).abs() < 1e-12);
+        assert!((samples[1] - preamp * REFERENCES[1].4[0]).abs() < 1e-9);
+        assert_eq!(samples[2], 0.0);
+        assert!(equalizer.set_preset(Some("missing")).is_err());
+    }
+}
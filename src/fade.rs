@@ -0,0 +1,358 @@
+//! Short volume ramps on play, pause, stop and seek.
+//!
+//! The ramps are applied to the samples by the fade sink, so they work with every volume
+//! controller and leave hardware volumes alone. To be able to fade out the audio when playback
+//! is paused, the fade sink holds back the length of a fade. The mixer is wrapped by a
+//! [`Fader`], so that the software volume is applied when the audio leaves the fade sink,
+//! instead of by the player, and volume changes aren't delayed.
+//!
+//! Seeks are found in the events of the player, which are read by the fade sink on the thread
+//! of the player. The player sends the `Seeked` event before it writes the first packet after
+//! the seek, so the event has always arrived when that packet is written.
+
+use librespot_playback::{
+    NUM_CHANNELS, SAMPLE_RATE,
+    audio_backend::{Sink, SinkResult},
+    convert::Converter,
+    decoder::AudioPacket,
+    dither::DithererBuilder,
+    mixer::{Mixer, MixerConfig, NoOpVolume, VolumeGetter},
+    player::{PlayerEvent, PlayerEventChannel},
+};
+use log::warn;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+fn duration_to_samples(duration: Duration) -> usize {
+    (duration.as_micros() * SAMPLE_RATE as u128 / 1_000_000) as usize * NUM_CHANNELS as usize
+}
+
+/// A mixer that leaves the software volume of another mixer to the fade sink.
+pub(crate) struct Fader {
+    mixer: Arc<dyn Mixer>,
+    soft_volume: Mutex<Box<dyn VolumeGetter + Send>>,
+    fade: Duration,
+    /// The events of a new player, until its fade sink takes them.
+    events: Mutex<Option<PlayerEventChannel>>,
+}
+
+impl Fader {
+    pub(crate) fn new(mixer: Arc<dyn Mixer>, fade: Duration) -> Self {
+        Self {
+            soft_volume: Mutex::new(mixer.get_soft_volume()),
+            mixer,
+            fade,
+            events: Mutex::default(),
+        }
+    }
+
+    /// The software volume of the mixer, 1.0 for hardware mixers.
+    fn attenuation(&self) -> f64 {
+        self.soft_volume
+            .lock()
+            .expect("fader has been poisoned")
+            .attenuation_factor()
+    }
+
+    /// Passes the events of a new player to its fade sink, to fade in after seeking.
+    pub(crate) fn follow(&self, events: PlayerEventChannel) {
+        *self.events.lock().expect("fader has been poisoned") = Some(events);
+    }
+}
+
+impl Mixer for Fader {
+    fn open(_: MixerConfig) -> Result<Self, librespot_core::Error> {
+        Err(librespot_core::Error::unimplemented(
+            "a fader wraps another mixer",
+        ))
+    }
+
+    fn volume(&self) -> u16 {
+        self.mixer.volume()
+    }
+
+    fn set_volume(&self, volume: u16) {
+        self.mixer.set_volume(volume);
+    }
+
+    // the software volume is applied by the fade sink
+    fn get_soft_volume(&self) -> Box<dyn VolumeGetter + Send> {
+        Box::new(NoOpVolume)
+    }
+}
+
+/// A linear change of the gain over a number of samples.
+struct Ramp {
+    from: f64,
+    to: f64,
+    pos: usize,
+    len: usize,
+}
+
+impl Ramp {
+    fn new(from: f64, to: f64, len: usize) -> Self {
+        Self {
+            from,
+            to,
+            pos: 0,
+            len: len.max(1),
+        }
+    }
+
+    fn gain(&self) -> f64 {
+        self.from + (self.to - self.from) * (self.pos as f64 / self.len as f64).min(1.0)
+    }
+}
+
+struct FadeSink {
+    sink: Box<dyn Sink>,
+    fader: Arc<Fader>,
+    /// The samples held back, to be faded out if playback is paused.
+    held: VecDeque<f64>,
+    fade_len: usize,
+    ramp: Option<Ramp>,
+    /// The events of the player, once it has passed them to the fader.
+    events: Option<PlayerEventChannel>,
+    /// Converts the tail that is faded out on stop, when the player's converter isn't available.
+    converter: Converter,
+}
+
+/// Fades the audio in and out, before it is written to the sink.
+pub(crate) fn wrap(
+    sink: Box<dyn Sink>,
+    fader: Arc<Fader>,
+    ditherer: Option<DithererBuilder>,
+) -> Box<dyn Sink> {
+    let fade_len = duration_to_samples(fader.fade);
+    Box::new(FadeSink {
+        sink,
+        fader,
+        held: VecDeque::with_capacity(fade_len),
+        fade_len,
+        ramp: None,
+        events: None,
+        converter: Converter::new(ditherer),
+    })
+}
+
+impl FadeSink {
+    fn gain(&self) -> f64 {
+        self.ramp.as_ref().map_or(1.0, Ramp::gain)
+    }
+
+    /// Whether the player has seeked since the last call.
+    fn seeked(&mut self) -> bool {
+        if let Some(events) = self
+            .fader
+            .events
+            .lock()
+            .expect("fader has been poisoned")
+            .take()
+        {
+            self.events = Some(events);
+        }
+        let Some(events) = &mut self.events else {
+            return false;
+        };
+        let mut seeked = false;
+        while let Ok(event) = events.try_recv() {
+            seeked |= matches!(event, PlayerEvent::Seeked { .. });
+        }
+        seeked
+    }
+
+    /// Writes the samples with the software volume, and the gain of the ramp while one is
+    /// running.
+    fn emit(&mut self, mut samples: Vec<f64>, converter: &mut Converter) -> SinkResult<()> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+        self.apply_gain(&mut samples);
+        self.sink.write(AudioPacket::Samples(samples), converter)
+    }
+
+    fn apply_gain(&mut self, samples: &mut [f64]) {
+        let attenuation = self.fader.attenuation();
+        match &mut self.ramp {
+            None if attenuation < 1.0 => {
+                samples.iter_mut().for_each(|sample| *sample *= attenuation);
+            }
+            None => (),
+            Some(ramp) => {
+                for frame in samples.chunks_mut(NUM_CHANNELS as usize) {
+                    let gain = attenuation * ramp.gain();
+                    frame.iter_mut().for_each(|sample| *sample *= gain);
+                    ramp.pos += frame.len();
+                }
+                if ramp.pos >= ramp.len {
+                    self.ramp = None;
+                }
+            }
+        }
+    }
+}
+
+impl Sink for FadeSink {
+    fn start(&mut self) -> SinkResult<()> {
+        // fading in anyway
+        self.seeked();
+        self.ramp = Some(Ramp::new(0.0, 1.0, self.fade_len));
+        self.sink.start()
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        self.ramp = Some(Ramp::new(self.gain(), 0.0, self.held.len()));
+        let mut held: Vec<f64> = self.held.drain(..).collect();
+        self.apply_gain(&mut held);
+        self.ramp = None;
+        // the player exits if stopping fails, which a lost output must not cause
+        if !held.is_empty()
+            && let Err(err) = self
+                .sink
+                .write(AudioPacket::Samples(held), &mut self.converter)
+        {
+            warn!("Failed to write the faded out audio: {err}");
+        }
+        self.sink.stop()
+    }
+
+    fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        let samples = match packet {
+            AudioPacket::Samples(samples) => samples,
+            raw => return self.sink.write(raw, converter),
+        };
+        if self.seeked() {
+            // fade out what was held back from before the seek, and fade in after it
+            self.ramp = Some(Ramp::new(self.gain(), 0.0, self.held.len()));
+            let held = self.held.drain(..).collect();
+            self.emit(held, converter)?;
+            self.ramp = Some(Ramp::new(0.0, 1.0, self.fade_len));
+        }
+        self.held.extend(samples);
+        let excess = self.held.len().saturating_sub(self.fade_len);
+        let samples = self.held.drain(..excess).collect();
+        self.emit(samples, converter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use librespot_core::{SpotifyId, SpotifyUri};
+    use librespot_playback::{
+        audio_backend::SinkError, config::VolumeCtrl, mixer::softmixer::SoftMixer,
+    };
+
+    struct TestSink(Arc<Mutex<Vec<f64>>>);
+
+    impl Sink for TestSink {
+        fn write(&mut self, packet: AudioPacket, _: &mut Converter) -> SinkResult<()> {
+            if let AudioPacket::Samples(samples) = packet {
+                self.0.lock().unwrap().extend(samples);
+            }
+            Ok(())
+        }
+    }
+
+    /// A sink whose device has gone away.
+    struct LostSink;
+
+    impl Sink for LostSink {
+        fn write(&mut self, _: AudioPacket, _: &mut Converter) -> SinkResult<()> {
+            Err(SinkError::OnWrite("the device has gone away".to_string()))
+        }
+    }
+
+    fn fader() -> Arc<Fader> {
+        let mixer = SoftMixer::open(MixerConfig {
+            volume_ctrl: VolumeCtrl::Linear,
+            ..MixerConfig::default()
+        })
+        .unwrap();
+        let fader = Arc::new(Fader::new(Arc::new(mixer), Duration::from_millis(100)));
+        fader.set_volume(u16::MAX);
+        fader
+    }
+
+    #[test]
+    fn test_fades() {
+        let fader = fader();
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let mut sink = wrap(Box::new(TestSink(written.clone())), fader.clone(), None);
+        let mut converter = Converter::new(None);
+        let fade_len = duration_to_samples(Duration::from_millis(100));
+
+        sink.start().unwrap();
+        sink.write(
+            AudioPacket::Samples(vec![1.0; 4 * fade_len]),
+            &mut converter,
+        )
+        .unwrap();
+        sink.stop().unwrap();
+
+        let written = written.lock().unwrap();
+        assert_eq!(written.len(), 4 * fade_len);
+        // fading in from silence
+        assert_eq!(written[0], 0.0);
+        assert!(written[fade_len / 2] > 0.4 && written[fade_len / 2] < 0.6);
+        assert_eq!(written[2 * fade_len], 1.0);
+        // and out when stopped
+        assert!(written[4 * fade_len - 1] < 0.15);
+        // the user volume is not affected
+        assert_eq!(fader.volume(), u16::MAX);
+    }
+
+    #[test]
+    fn test_seek() {
+        let fader = fader();
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let mut sink = wrap(Box::new(TestSink(written.clone())), fader.clone(), None);
+        let mut converter = Converter::new(None);
+        let fade_len = duration_to_samples(Duration::from_millis(100));
+        let (events_tx, events) = tokio::sync::mpsc::unbounded_channel();
+        fader.follow(events);
+
+        sink.start().unwrap();
+        sink.write(
+            AudioPacket::Samples(vec![1.0; 2 * fade_len]),
+            &mut converter,
+        )
+        .unwrap();
+        events_tx
+            .send(PlayerEvent::Seeked {
+                play_request_id: 0,
+                track_id: SpotifyUri::Track {
+                    id: SpotifyId::from_raw(&[1; 16]).unwrap(),
+                },
+                position_ms: 0,
+            })
+            .unwrap();
+        sink.write(
+            AudioPacket::Samples(vec![1.0; 2 * fade_len]),
+            &mut converter,
+        )
+        .unwrap();
+
+        // what was held back before the seek is faded out, and the audio after it faded in
+        let written = written.lock().unwrap();
+        assert_eq!(written.len(), 3 * fade_len);
+        assert!(written[2 * fade_len - 1] < 0.05);
+        assert_eq!(written[2 * fade_len], 0.0);
+    }
+
+    #[test]
+    fn test_stop_without_device() {
+        let mut sink = wrap(Box::new(LostSink), fader(), None);
+        let mut converter = Converter::new(None);
+        let fade_len = duration_to_samples(Duration::from_millis(100));
+
+        sink.start().unwrap();
+        // held back, to be faded out on stop
+        sink.write(AudioPacket::Samples(vec![1.0; fade_len]), &mut converter)
+            .unwrap();
+        sink.stop().unwrap();
+    }
+}
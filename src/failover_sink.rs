@@ -0,0 +1,272 @@
+//! A sink that recovers from audio devices that disappear, e.g. an unplugged USB DAC.
+//!
+//! If the output fails, playback continues on the first available of the fallback outputs.
+//! If none is available, playback is paused. In both cases, the preferred outputs are probed
+//! in the background, until one comes back and playback can switch back to it or resume.
+
+use crate::main_loop::AudioOutput;
+use librespot_playback::{
+    audio_backend::{Sink, SinkError, SinkResult},
+    convert::Converter,
+    decoder::AudioPacket,
+};
+use log::{error, info, warn};
+use std::{
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+/// How often the preferred outputs are probed, until one of them comes back.
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Watches the outputs, which are preferred over the current one.
+#[derive(Default)]
+pub(crate) struct OutputMonitor {
+    /// Notified when playback can be resumed, because an output is available again.
+    pub(crate) resume: Notify,
+    /// The number of outputs that would be preferred over the current one.
+    preferred: AtomicUsize,
+    /// Whether playback has been paused, because no output was available.
+    paused: AtomicBool,
+    preferred_available: AtomicBool,
+    probing: AtomicBool,
+}
+
+impl OutputMonitor {
+    /// Probes the preferred outputs in the background, if that isn't happening already.
+    ///
+    /// The probing goes on until a preferred output can be opened, or the monitor is dropped.
+    fn probe(self: &Arc<Self>, outputs: &[AudioOutput]) {
+        if self.probing.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let monitor = Arc::downgrade(self);
+        let outputs = outputs.to_vec();
+        thread::spawn(move || probe_until_available(monitor, &outputs));
+    }
+}
+
+fn probe_until_available(monitor: Weak<OutputMonitor>, outputs: &[AudioOutput]) {
+    loop {
+        thread::sleep(PROBE_INTERVAL);
+        let Some(monitor) = monitor.upgrade() else {
+            return;
+        };
+        let preferred = monitor.preferred.load(Ordering::SeqCst);
+        let available = outputs[..preferred.min(outputs.len())]
+            .iter()
+            .find(|output| probe(output));
+        if preferred == 0 || available.is_some() {
+            if let Some(output) = available {
+                info!("The audio output {} is available again", output.name);
+                if monitor.paused.swap(false, Ordering::SeqCst) {
+                    monitor.resume.notify_one();
+                } else {
+                    monitor.preferred_available.store(true, Ordering::SeqCst);
+                }
+            }
+            monitor.probing.store(false, Ordering::SeqCst);
+            return;
+        }
+    }
+}
+
+/// Checks whether an output can be opened.
+fn probe(output: &AudioOutput) -> bool {
+    let mut sink = output.open();
+    let available = sink.start().is_ok();
+    if available {
+        let _ = sink.stop();
+    }
+    available
+}
+
+struct FailoverSink {
+    /// The outputs, from the most to the least preferred one.
+    outputs: Vec<AudioOutput>,
+    current: Option<(usize, Box<dyn Sink>)>,
+    monitor: Arc<OutputMonitor>,
+}
+
+pub(crate) fn open(outputs: Vec<AudioOutput>, monitor: Arc<OutputMonitor>) -> Box<dyn Sink> {
+    Box::new(FailoverSink {
+        outputs,
+        current: None,
+        monitor,
+    })
+}
+
+impl FailoverSink {
+    /// Starts the first available output.
+    fn open_output(&mut self) -> SinkResult<()> {
+        self.current = None;
+        self.monitor
+            .preferred_available
+            .store(false, Ordering::SeqCst);
+        let mut last_err = None;
+        for (index, output) in self.outputs.iter().enumerate() {
+            let mut sink = output.open();
+            match sink.start() {
+                Ok(()) => {
+                    self.monitor.preferred.store(index, Ordering::SeqCst);
+                    self.monitor.paused.store(false, Ordering::SeqCst);
+                    if index > 0 {
+                        warn!("Falling back to the audio output {}", output.name);
+                        self.monitor.probe(&self.outputs);
+                    }
+                    self.current = Some((index, sink));
+                    return Ok(());
+                }
+                Err(err) => {
+                    warn!("The audio output {} is not available: {err}", output.name);
+                    last_err = Some(err);
+                }
+            }
+        }
+        warn!("No audio output is available, pausing playback until one comes back");
+        self.monitor
+            .preferred
+            .store(self.outputs.len(), Ordering::SeqCst);
+        self.monitor.paused.store(true, Ordering::SeqCst);
+        self.monitor.probe(&self.outputs);
+        Err(last_err.expect("there is at least one output"))
+    }
+}
+
+impl Sink for FailoverSink {
+    fn start(&mut self) -> SinkResult<()> {
+        if self.monitor.preferred_available.load(Ordering::SeqCst) {
+            return self.open_output();
+        }
+        if let Some((index, sink)) = &mut self.current {
+            match sink.start() {
+                Ok(()) => return Ok(()),
+                Err(err) => warn!(
+                    "Failed to restart the audio output {}: {err}",
+                    self.outputs[*index].name
+                ),
+            }
+        }
+        self.open_output()
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        if let Some((index, sink)) = &mut self.current
+            && let Err(err) = sink.stop()
+        {
+            warn!(
+                "Failed to stop the audio output {}: {err}",
+                self.outputs[*index].name
+            );
+            self.current = None;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        if self.monitor.preferred_available.load(Ordering::SeqCst) {
+            if let Some((_, sink)) = &mut self.current {
+                let _ = sink.stop();
+            }
+            self.open_output()?;
+        }
+        let Some((index, sink)) = &mut self.current else {
+            return Err(SinkError::NotConnected(
+                "<FailoverSink> no audio output is available".to_string(),
+            ));
+        };
+        if let Err(err) = sink.write(packet, converter) {
+            error!(
+                "The audio output {} failed: {err}",
+                self.outputs[*index].name
+            );
+            // continue on a fallback right away, or pause until an output comes back
+            self.open_output()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use librespot_playback::config::AudioFormat;
+
+    /// A sink that only works while the device is plugged in.
+    struct TestSink {
+        plugged_in: Arc<AtomicBool>,
+    }
+
+    impl Sink for TestSink {
+        fn start(&mut self) -> SinkResult<()> {
+            if self.plugged_in.load(Ordering::SeqCst) {
+                Ok(())
+            } else {
+                Err(SinkError::ConnectionRefused("unplugged".to_string()))
+            }
+        }
+
+        fn write(&mut self, _: AudioPacket, _: &mut Converter) -> SinkResult<()> {
+            self.start()
+                .map_err(|_| SinkError::OnWrite("unplugged".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_failover() {
+        let output = |name: &str, plugged_in: &Arc<AtomicBool>| {
+            let plugged_in = plugged_in.clone();
+            AudioOutput {
+                name: name.to_string(),
+                builder: Arc::new(move |_, _| {
+                    Box::new(TestSink {
+                        plugged_in: plugged_in.clone(),
+                    })
+                }),
+                device: None,
+                format: AudioFormat::S16,
+            }
+        };
+        let dac = Arc::new(AtomicBool::new(true));
+        let hdmi = Arc::new(AtomicBool::new(true));
+        let monitor = Arc::new(OutputMonitor::default());
+        let mut sink = open(
+            vec![output("dac", &dac), output("hdmi", &hdmi)],
+            monitor.clone(),
+        );
+        let mut converter = Converter::new(None);
+        let mut write =
+            |sink: &mut Box<dyn Sink>| sink.write(AudioPacket::Samples(vec![0.0]), &mut converter);
+
+        sink.start().unwrap();
+        write(&mut sink).unwrap();
+
+        // unplugging the DAC falls back to HDMI
+        dac.store(false, Ordering::SeqCst);
+        write(&mut sink).unwrap();
+        assert_eq!(monitor.preferred.load(Ordering::SeqCst), 1);
+
+        // without any output, playback is paused until one comes back
+        hdmi.store(false, Ordering::SeqCst);
+        assert!(write(&mut sink).is_err());
+        assert!(monitor.paused.load(Ordering::SeqCst));
+        dac.store(true, Ordering::SeqCst);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            tokio::time::timeout(Duration::from_secs(10), monitor.resume.notified())
+                .await
+                .expect("playback should be resumed");
+        });
+        sink.start().unwrap();
+        write(&mut sink).unwrap();
+        assert_eq!(monitor.preferred.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,488 @@
+//! A small FLAC encoder, producing the Ogg FLAC stream of the network sink.
+//!
+//! Only what is needed for streaming 16 bit PCM is implemented: frames with a fixed block
+//! size, fixed linear predictors and rice coded residuals in a single partition. That already
+//! compresses music reasonably well, without pulling in a native encoder library.
+//!
+//! Ogg/Opus isn't offered: the only Opus encoders for Rust bind libopus, which would be another
+//! native library to install and cross-compile for every target, while the existing pure Rust
+//! FLAC encoders come with far more than the few hundred lines needed here. Since the stream
+//! is meant for the local network, the bandwidth of FLAC is acceptable.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The number of samples per channel in a frame.
+const BLOCK_SIZE: usize = 4096;
+const BITS_PER_SAMPLE: u32 = 16;
+const MAX_FIXED_ORDER: usize = 4;
+const MAX_RICE_PARAMETER: u32 = 14;
+
+const OGG_CONTINUED: u8 = 0x01;
+const OGG_BOS: u8 = 0x02;
+const OGG_EOS: u8 = 0x04;
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    len: u32,
+}
+
+impl BitWriter {
+    /// Writes the lowest `bits` bits of `value`, at most 32.
+    fn write(&mut self, value: u64, bits: u32) {
+        debug_assert!(bits <= 32);
+        self.acc = (self.acc << bits) | (value & ((1 << bits) - 1));
+        self.len += bits;
+        while self.len >= 8 {
+            self.len -= 8;
+            self.bytes.push((self.acc >> self.len) as u8);
+        }
+        self.acc &= (1 << self.len) - 1;
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    /// Writes `zeros` zero bits, followed by a one.
+    fn write_unary(&mut self, mut zeros: u64) {
+        while zeros >= 32 {
+            self.write(0, 32);
+            zeros -= 32;
+        }
+        self.write(1, zeros as u32 + 1);
+    }
+
+    fn align(&mut self) {
+        if self.len > 0 {
+            self.write(0, 8 - self.len);
+        }
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |mut crc, &byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |mut crc, &byte| {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+fn ogg_crc(data: &[u8]) -> u32 {
+    data.iter().fold(0, |mut crc, &byte| {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+/// Writes the frame number in the UTF-8 like coding of FLAC frame headers.
+fn write_utf8(writer: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        writer.write(value, 8);
+        return;
+    }
+    let bits = u64::BITS - value.leading_zeros();
+    // the first byte holds 7 - n bits, every following one 6 bits
+    let mut n = 2;
+    while 5 * n + 1 < bits {
+        n += 1;
+    }
+    let prefix = (0xff00 >> n) & 0xff;
+    writer.write(prefix | (value >> (6 * (n - 1))), 8);
+    for i in (0..n - 1).rev() {
+        writer.write(0x80 | ((value >> (6 * i)) & 0x3f), 8);
+    }
+}
+
+fn zigzag(residual: i64) -> u64 {
+    ((residual << 1) ^ (residual >> 63)) as u64
+}
+
+fn encode_subframe(writer: &mut BitWriter, samples: &[i64]) {
+    // the residuals of the fixed predictor of order n are the n-th differences
+    let mut residuals = samples.to_vec();
+    let mut best = (0, residuals.clone());
+    let mut best_sum: u64 = residuals.iter().map(|r| r.unsigned_abs()).sum();
+    for order in 1..=MAX_FIXED_ORDER.min(samples.len()) {
+        residuals = residuals.windows(2).map(|w| w[1] - w[0]).collect();
+        let sum = residuals.iter().map(|r| r.unsigned_abs()).sum();
+        if sum < best_sum {
+            best = (order, residuals.clone());
+            best_sum = sum;
+        }
+    }
+    let (order, residuals) = best;
+
+    let (rice_parameter, residual_bits) = (0..=MAX_RICE_PARAMETER)
+        .map(|k| {
+            let bits: u64 = residuals
+                .iter()
+                .map(|&r| (zigzag(r) >> k) + 1 + k as u64)
+                .sum();
+            (k, bits)
+        })
+        .min_by_key(|(_, bits)| *bits)
+        .expect("the range of rice parameters is not empty");
+    let fixed_bits = order as u64 * BITS_PER_SAMPLE as u64 + 10 + residual_bits;
+    let verbatim_bits = samples.len() as u64 * BITS_PER_SAMPLE as u64;
+
+    writer.write(0, 1);
+    if fixed_bits < verbatim_bits {
+        writer.write(0b001000 | order as u64, 6);
+        writer.write(0, 1);
+        for &sample in &samples[..order] {
+            writer.write_signed(sample, BITS_PER_SAMPLE);
+        }
+        // rice coding with a 4 bit parameter and a single partition
+        writer.write(0, 2);
+        writer.write(0, 4);
+        writer.write(rice_parameter as u64, 4);
+        for &residual in &residuals {
+            let value = zigzag(residual);
+            writer.write_unary(value >> rice_parameter);
+            writer.write(value, rice_parameter);
+        }
+    } else {
+        writer.write(0b000001, 6);
+        writer.write(0, 1);
+        for &sample in samples {
+            writer.write_signed(sample, BITS_PER_SAMPLE);
+        }
+    }
+}
+
+/// Splits packets into Ogg pages of one logical stream.
+struct OggStream {
+    serial: u32,
+    sequence: u32,
+}
+
+impl OggStream {
+    fn write_page(
+        &mut self,
+        out: &mut Vec<u8>,
+        flags: u8,
+        granule: u64,
+        lacing: &[u8],
+        data: &[u8],
+    ) {
+        let start = out.len();
+        out.extend_from_slice(b"OggS");
+        out.push(0);
+        out.push(flags);
+        out.extend_from_slice(&granule.to_le_bytes());
+        out.extend_from_slice(&self.serial.to_le_bytes());
+        out.extend_from_slice(&self.sequence.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.push(lacing.len() as u8);
+        out.extend_from_slice(lacing);
+        out.extend_from_slice(data);
+        let crc = ogg_crc(&out[start..]);
+        out[start + 22..start + 26].copy_from_slice(&crc.to_le_bytes());
+        self.sequence += 1;
+    }
+
+    /// Writes a packet in pages of its own, which end at the given granule position.
+    fn write_packet(&mut self, out: &mut Vec<u8>, packet: &[u8], granule: u64, flags: u8) {
+        let mut lacing = vec![255; packet.len() / 255];
+        lacing.push((packet.len() % 255) as u8);
+        let pages = lacing.len().div_ceil(255);
+        let mut data = packet;
+        for (i, lacing) in lacing.chunks(255).enumerate() {
+            let len = lacing.iter().map(|&l| l as usize).sum();
+            let mut page_flags = 0;
+            if i == 0 {
+                page_flags |= flags & OGG_BOS;
+            } else {
+                page_flags |= OGG_CONTINUED;
+            }
+            let last = i == pages - 1;
+            if last {
+                page_flags |= flags & OGG_EOS;
+            }
+            // pages on which no packet ends don't have a granule position
+            let page_granule = if last { granule } else { u64::MAX };
+            self.write_page(out, page_flags, page_granule, lacing, &data[..len]);
+            data = &data[len..];
+        }
+    }
+}
+
+/// Encodes interleaved 16 bit samples into a (chained) Ogg FLAC stream.
+///
+/// Every change of the comments (i.e. the title) starts a new logical stream, which is how
+/// Ogg streams carry metadata updates.
+pub(crate) struct OggFlacEncoder {
+    sample_rate: u32,
+    channels: usize,
+    comments: Vec<(String, String)>,
+    ogg: OggStream,
+    headers: Vec<u8>,
+    pending: Vec<i16>,
+    frame_number: u64,
+    granule: u64,
+}
+
+impl OggFlacEncoder {
+    pub(crate) fn new(sample_rate: u32, channels: usize) -> Self {
+        let serial = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.subsec_nanos())
+            ^ std::process::id();
+        let mut encoder = Self {
+            sample_rate,
+            channels,
+            comments: Vec::new(),
+            ogg: OggStream {
+                serial,
+                sequence: 0,
+            },
+            headers: Vec::new(),
+            pending: Vec::new(),
+            frame_number: 0,
+            granule: 0,
+        };
+        encoder.start_stream();
+        encoder
+    }
+
+    /// The header pages of the current logical stream, which new listeners need first.
+    pub(crate) fn headers(&self) -> &[u8] {
+        &self.headers
+    }
+
+    fn start_stream(&mut self) {
+        self.frame_number = 0;
+        self.granule = 0;
+
+        let mut stream_info = BitWriter::default();
+        stream_info.write(BLOCK_SIZE as u64, 16);
+        stream_info.write(BLOCK_SIZE as u64, 16);
+        // unknown minimum and maximum frame sizes
+        stream_info.write(0, 24);
+        stream_info.write(0, 24);
+        stream_info.write(self.sample_rate as u64, 20);
+        stream_info.write(self.channels as u64 - 1, 3);
+        stream_info.write(BITS_PER_SAMPLE as u64 - 1, 5);
+        // unknown number of samples and MD5 sum
+        stream_info.write(0, 4);
+        for _ in 0..5 {
+            stream_info.write(0, 32);
+        }
+
+        let mut first = b"\x7fFLAC\x01\x00\x00\x01fLaC".to_vec();
+        first.extend_from_slice(&[0, 0, 0, stream_info.bytes.len() as u8]);
+        first.extend_from_slice(&stream_info.bytes);
+
+        let mut comment = Vec::new();
+        let vendor = concat!("spotifyd ", env!("CARGO_PKG_VERSION"));
+        comment.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        comment.extend_from_slice(vendor.as_bytes());
+        comment.extend_from_slice(&(self.comments.len() as u32).to_le_bytes());
+        for (key, value) in &self.comments {
+            let entry = format!("{key}={value}");
+            comment.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+            comment.extend_from_slice(entry.as_bytes());
+        }
+        // the last metadata block, of type VORBIS_COMMENT
+        let mut second = vec![0x84];
+        second.extend_from_slice(&(comment.len() as u32).to_be_bytes()[1..]);
+        second.extend_from_slice(&comment);
+
+        self.headers.clear();
+        self.ogg.write_packet(&mut self.headers, &first, 0, OGG_BOS);
+        self.ogg.write_packet(&mut self.headers, &second, 0, 0);
+    }
+
+    fn encode_frame(&mut self, block: &[i16]) -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        // sync code and fixed block size
+        writer.write(0xfff8, 16);
+        // a block size of 4096 samples
+        writer.write(0b1100, 4);
+        let sample_rate_code = match self.sample_rate {
+            44100 => 0b1001,
+            48000 => 0b1010,
+            96000 => 0b1011,
+            // taken from the stream info
+            _ => 0b0000,
+        };
+        writer.write(sample_rate_code, 4);
+        // independent channels
+        writer.write(self.channels as u64 - 1, 4);
+        // 16 bits per sample
+        writer.write(0b100, 3);
+        writer.write(0, 1);
+        write_utf8(&mut writer, self.frame_number);
+        let crc = crc8(&writer.bytes);
+        writer.write(crc as u64, 8);
+
+        for channel in 0..self.channels {
+            let samples: Vec<i64> = block
+                .iter()
+                .skip(channel)
+                .step_by(self.channels)
+                .map(|&sample| sample as i64)
+                .collect();
+            encode_subframe(&mut writer, &samples);
+        }
+        writer.align();
+        let crc = crc16(&writer.bytes);
+        writer.write(crc as u64, 16);
+
+        self.frame_number += 1;
+        writer.bytes
+    }
+
+    /// Encodes the samples and returns the completed pages.
+    pub(crate) fn encode(&mut self, samples: &[i16]) -> Vec<u8> {
+        self.pending.extend_from_slice(samples);
+        let mut out = Vec::new();
+        let block_len = BLOCK_SIZE * self.channels;
+        while self.pending.len() >= block_len {
+            let block: Vec<i16> = self.pending.drain(..block_len).collect();
+            let frame = self.encode_frame(&block);
+            self.granule += BLOCK_SIZE as u64;
+            self.ogg.write_packet(&mut out, &frame, self.granule, 0);
+        }
+        out
+    }
+
+    /// Pads the pending samples with silence to a complete frame and returns its pages.
+    pub(crate) fn flush(&mut self) -> Vec<u8> {
+        if self.pending.is_empty() {
+            return Vec::new();
+        }
+        let block_len = BLOCK_SIZE * self.channels;
+        let padding = block_len - self.pending.len();
+        self.encode(&vec![0; padding])
+    }
+
+    /// Ends the current logical stream and starts a new one with the given comments.
+    ///
+    /// Samples that don't fill a frame yet are encoded in the new stream, instead of padding
+    /// them with silence, which would leave a gap at every change of the track.
+    pub(crate) fn set_comments(&mut self, comments: Vec<(String, String)>) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.ogg
+            .write_page(&mut out, OGG_EOS, self.granule, &[], &[]);
+        self.comments = comments;
+        self.next_stream();
+        out.extend_from_slice(&self.headers);
+        out
+    }
+
+    /// Starts a new logical stream with the same comments, dropping the pending samples, for
+    /// when the previous one hasn't been sent to anybody.
+    pub(crate) fn restart(&mut self) {
+        self.pending.clear();
+        self.next_stream();
+    }
+
+    fn next_stream(&mut self) {
+        self.ogg = OggStream {
+            serial: self.ogg.serial.wrapping_add(1),
+            sequence: 0,
+        };
+        self.start_stream();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use symphonia::core::{
+        audio::SampleBuffer,
+        codecs::DecoderOptions,
+        formats::FormatOptions,
+        io::{MediaSourceStream, MediaSourceStreamOptions},
+        meta::MetadataOptions,
+        probe::Hint,
+    };
+
+    #[test]
+    fn test_ogg_flac_roundtrip() {
+        let samples: Vec<i16> = (0..3 * BLOCK_SIZE)
+            .flat_map(|i| {
+                let t = i as f64 / 44100.0;
+                let left = (t * 440.0 * std::f64::consts::TAU).sin() * 12000.0;
+                // a bit of noise, so that the predictors don't work perfectly
+                let right = ((i * 7919) % 2000) as f64 - 1000.0;
+                [left as i16, right as i16]
+            })
+            .collect();
+
+        let mut encoder = OggFlacEncoder::new(44100, 2);
+        let mut stream = encoder.headers().to_vec();
+        stream.extend(encoder.encode(&samples[..5000]));
+        stream.extend(encoder.encode(&samples[5000..]));
+        assert!(encoder.flush().is_empty());
+        assert!(stream.len() < samples.len() * 2);
+
+        let source = MediaSourceStream::new(
+            Box::new(Cursor::new(stream)),
+            MediaSourceStreamOptions::default(),
+        );
+        let mut hint = Hint::new();
+        hint.mime_type("application/ogg");
+        let mut format = symphonia::default::get_probe()
+            .format(
+                &hint,
+                source,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )
+            .unwrap()
+            .format;
+        let track = format.default_track().unwrap();
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions { verify: true })
+            .unwrap();
+
+        let mut decoded: Vec<i16> = Vec::new();
+        while let Ok(packet) = format.next_packet() {
+            let audio = decoder.decode(&packet).unwrap();
+            let mut buffer = SampleBuffer::<i16>::new(audio.capacity() as u64, *audio.spec());
+            buffer.copy_interleaved_ref(audio);
+            decoded.extend_from_slice(buffer.samples());
+        }
+        assert_eq!(decoded, samples);
+
+        // the start of the next frame is carried over into the next stream
+        assert!(encoder.encode(&samples[..5000]).is_empty());
+        let chained = encoder.set_comments(vec![("TITLE".to_string(), "Song".to_string())]);
+        assert!(chained.starts_with(b"OggS"));
+        assert!(encoder.headers().windows(10).any(|w| w == b"TITLE=Song"));
+        assert_eq!(encoder.pending, samples[..5000]);
+        assert!(!encoder.encode(&samples[..2 * BLOCK_SIZE - 5000]).is_empty());
+    }
+}
@@ -7,7 +7,9 @@ use config::ExecutionMode;
 #[cfg(unix)]
 use daemonize::Daemonize;
 use fern::colors::ColoredLevelConfig;
-use log::{LevelFilter, info, trace};
+use futures::future;
+use log::{LevelFilter, error, info, trace};
+use main_loop::{DeviceRegistry, MainLoop};
 use oauth::run_oauth;
 #[cfg(target_os = "openbsd")]
 use pledge::pledge;
@@ -15,18 +17,38 @@ use pledge::pledge;
 use std::fs;
 use tokio::runtime::Runtime;
 
+mod accounts;
 #[cfg(feature = "alsa_backend")]
 mod alsa_mixer;
+mod auth;
+mod command_mixer;
 mod config;
+mod crossfade;
 #[cfg(feature = "dbus_mpris")]
 mod dbus_mpris;
+mod duck;
+mod equalizer;
 mod error;
+mod fade;
+mod failover_sink;
+mod flac;
 mod main_loop;
+mod mute;
+mod network_sink;
 mod no_mixer;
 mod oauth;
 mod process;
+#[cfg(feature = "pulseaudio_backend")]
+mod pulse_mixer;
+#[cfg(feature = "pulseaudio_backend")]
+mod pulse_sink;
 mod setup;
+#[cfg(unix)]
+mod snapcast;
+mod tee_sink;
 mod utils;
+mod volume_limit;
+mod volume_memory;
 
 enum LogTarget {
     Terminal,
@@ -114,11 +136,18 @@ fn main() -> eyre::Result<()> {
 
     color_eyre::install().wrap_err("Couldn't initialize error reporting")?;
 
-    let cli_config = CliConfig::parse();
+    let mut cli_config = CliConfig::parse();
 
-    match cli_config.mode {
+    match cli_config.mode.take() {
         None => run_daemon(cli_config),
-        Some(ExecutionMode::Authenticate { oauth_port }) => run_oauth(cli_config, oauth_port),
+        Some(ExecutionMode::Authenticate(mut args)) => match args.action.take() {
+            Some(action) => auth::run_auth_command(cli_config, action),
+            None => run_oauth(cli_config, args),
+        },
+        #[cfg(unix)]
+        Some(ExecutionMode::SnapcastControl(args)) => snapcast::run_control_bridge(&args.socket),
+        #[cfg(feature = "dbus_mpris")]
+        Some(ExecutionMode::Sleep(args)) => dbus_mpris::run_sleep_command(cli_config, args),
     }
 }
 
@@ -156,8 +185,20 @@ fn run_daemon(mut cli_config: CliConfig) -> eyre::Result<()> {
         .wrap_err("could not load the config file")?;
     trace!("{:?}", &cli_config);
 
-    // Returns the old SpotifydConfig struct used within the rest of the daemon.
-    let internal_config = config::get_internal_config(cli_config);
+    // Returns the old SpotifydConfig structs used within the rest of the daemon.
+    let internal_configs =
+        config::get_internal_configs(cli_config).wrap_err("invalid device configuration")?;
+    if internal_configs.len() > 1 {
+        info!(
+            "Running {} devices: {}",
+            internal_configs.len(),
+            internal_configs
+                .iter()
+                .map(|config| config.device_name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
 
     if is_daemon {
         info!("Daemonizing running instance");
@@ -165,7 +206,7 @@ fn run_daemon(mut cli_config: CliConfig) -> eyre::Result<()> {
         #[cfg(unix)]
         {
             let mut daemonize = Daemonize::new();
-            if let Some(pid) = internal_config.pid.as_ref() {
+            if let Some(pid) = internal_configs[0].pid.as_ref() {
                 daemonize = daemonize.pid_file(pid);
             }
             match daemonize.start() {
@@ -212,7 +253,10 @@ fn run_daemon(mut cli_config: CliConfig) -> eyre::Result<()> {
         // > after sndio(7) cookie  "audio"
 
         // --on-song-change-hook aka. "onevent", run via --shell aka. "shell"
-        if internal_config.onevent.is_some() {
+        if internal_configs
+            .iter()
+            .any(|config| config.onevent.is_some())
+        {
             pledge(
                 "stdio rpath wpath cpath inet mcast unix dns proc exec audio",
                 None,
@@ -225,7 +269,23 @@ fn run_daemon(mut cli_config: CliConfig) -> eyre::Result<()> {
 
     let runtime = Runtime::new().unwrap();
     runtime.block_on(async {
-        let initial_state = setup::initial_state(internal_config)?;
-        initial_state.run().await
+        let devices = DeviceRegistry::default();
+        let main_loops = internal_configs
+            .into_iter()
+            .map(|config| setup::initial_state(config, devices.clone()))
+            .collect::<eyre::Result<Vec<_>>>()?;
+
+        // a failing device shouldn't take down the other ones
+        let mut results = future::join_all(main_loops.into_iter().map(MainLoop::run))
+            .await
+            .into_iter()
+            .filter_map(Result::err);
+        let Some(err) = results.next() else {
+            return Ok(());
+        };
+        for additional_err in results {
+            error!("additional error while running devices: {additional_err:?}");
+        }
+        Err(err)
     })
 }
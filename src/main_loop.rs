@@ -1,9 +1,25 @@
+use crate::accounts::Accounts;
 #[cfg(feature = "dbus_mpris")]
 use crate::config::{DBusType, MprisConfig};
+use crate::config::{StoredName, TakeoverPolicy};
+use crate::crossfade::Crossfade;
 #[cfg(feature = "dbus_mpris")]
-use crate::dbus_mpris::DbusServer;
-use crate::process::spawn_program_on_event;
+use crate::dbus_mpris::{DbusContext, DbusServer};
+#[cfg(unix)]
+use crate::duck;
+use crate::duck::Ducker;
+#[cfg(feature = "dbus_mpris")]
+use crate::equalizer::Equalizer;
+use crate::fade::Fader;
+use crate::failover_sink::OutputMonitor;
+use crate::mute::MuteMixer;
+use crate::network_sink::StreamOutput;
+use crate::process::{HookEvent, spawn_program_on_event, spawn_program_on_hook_event};
+#[cfg(unix)]
+use crate::snapcast::SnapcastServer;
 use crate::utils::Backoff;
+use crate::volume_limit;
+use crate::volume_memory::VolumeMemory;
 use color_eyre::eyre::{self, Context};
 use futures::future::Either;
 #[cfg(not(feature = "dbus_mpris"))]
@@ -23,53 +39,320 @@ use librespot_playback::{
     audio_backend::Sink,
     config::{AudioFormat, PlayerConfig},
     mixer::Mixer,
-    player::Player,
+    player::{Player, PlayerEvent},
 };
-use log::{error, info};
+use log::{error, info, warn};
+use std::fmt;
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// How long the sleep timer fades out before pausing playback.
+const SLEEP_FADE: Duration = Duration::from_secs(10);
 
 #[cfg(not(feature = "dbus_mpris"))]
 type DbusServer = Pending<()>;
 
+/// Requests sent to the main loop by one of the control interfaces.
+#[derive(Debug)]
+#[cfg_attr(not(feature = "dbus_mpris"), expect(dead_code))]
+pub(crate) enum MainLoopCommand {
+    /// Reconnect using the credentials of the given named account, or of the default one.
+    SwitchAccount(Option<String>),
+    /// Replace the current session with the discovery login deferred by the takeover policy.
+    ForceTakeover,
+    /// Change the takeover policy.
+    SetTakeoverPolicy(TakeoverPolicy),
+    /// Change the name shown in Spotify clients, keeping the device id.
+    Rename(String),
+    /// Change whether the device is announced as a speaker group.
+    SetGroup(bool),
+    /// Start the sleep timer, or cancel it with `None`.
+    SetSleepTimer(Option<SleepTimer>),
+}
+
+/// When the sleep timer pauses playback.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "dbus_mpris"), expect(dead_code))]
+pub(crate) enum SleepTimer {
+    /// At the given time, after fading out.
+    At(Instant),
+    /// At the end of the current track or episode.
+    EndOfTrack,
+}
+
+impl SleepTimer {
+    /// The time left until playback is paused, unless that depends on the track.
+    pub(crate) fn remaining(&self) -> Option<Duration> {
+        match self {
+            Self::At(at) => Some(at.saturating_duration_since(Instant::now())),
+            Self::EndOfTrack => None,
+        }
+    }
+}
+
+/// What happened to the sleep timer, as passed to the hook.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SleepTimerAction {
+    Set,
+    Cancelled,
+    Expired,
+}
+
+impl fmt::Display for SleepTimerAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Set => "set",
+            Self::Cancelled => "cancelled",
+            Self::Expired => "expired",
+        })
+    }
+}
+
+/// When a track of the given length ends, if it plays on from the position.
+fn end_of_track(duration: Option<Duration>, position_ms: u32) -> Option<Instant> {
+    let position = Duration::from_millis(position_ms.into());
+    duration.map(|duration| Instant::now() + duration.saturating_sub(position))
+}
+
+/// Sleeps until the given time, or forever.
+async fn sleep_until(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at.into()).await,
+        None => future::pending().await,
+    }
+}
+
+/// The state of a single device, as shown in the combined status view.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(not(feature = "dbus_mpris"), expect(dead_code))]
+pub(crate) struct DeviceStatus {
+    pub(crate) name: String,
+    pub(crate) bus_name: Option<String>,
+    pub(crate) username: Option<String>,
+    pub(crate) playing: bool,
+    /// Whether the current Connect session, or the last one, has been started as a speaker
+    /// group, as configured.
+    pub(crate) is_group: bool,
+    pub(crate) takeover_policy: TakeoverPolicy,
+    /// The user of a discovery login that has been deferred by the takeover policy.
+    pub(crate) pending_takeover: Option<String>,
+    pub(crate) sleep_timer: Option<SleepTimer>,
+}
+
+/// The status of all devices that are run by this process.
+pub(crate) type DeviceRegistry = Arc<RwLock<Vec<DeviceStatus>>>;
+
+/// Decides which Spotify users may take over the device via discovery.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DiscoveryAccess {
+    pub(crate) allowed_users: Vec<String>,
+    pub(crate) denied_users: Vec<String>,
+    /// If set, only this user is accepted.
+    pub(crate) owner: Option<String>,
+}
+
+impl DiscoveryAccess {
+    pub(crate) fn check(&self, creds: &Credentials) -> Result<(), RejectedConnection> {
+        let username = creds.username.as_deref().unwrap_or_default();
+        let matches = |user: &String| user.eq_ignore_ascii_case(username);
+
+        let reason = if self.denied_users.iter().any(matches) {
+            "denied"
+        } else if let Some(owner) = &self.owner {
+            if matches(owner) {
+                return Ok(());
+            }
+            "not the owner"
+        } else if !self.allowed_users.is_empty() && !self.allowed_users.iter().any(matches) {
+            "not allowed"
+        } else {
+            return Ok(());
+        };
+        Err(RejectedConnection {
+            username: creds.username.clone(),
+            reason,
+        })
+    }
+}
+
+/// A discovery login that has been refused by [`DiscoveryAccess`].
+#[derive(Debug)]
+pub(crate) struct RejectedConnection {
+    username: Option<String>,
+    reason: &'static str,
+}
+
+/// Everything needed to (re-)launch the discovery service.
+#[derive(Clone, Debug)]
+pub(crate) struct DiscoveryConfig {
+    pub(crate) device_id: String,
+    pub(crate) client_id: String,
+    pub(crate) device_type: DeviceType,
+    pub(crate) is_group: bool,
+    pub(crate) zeroconf_ip: Vec<IpAddr>,
+    pub(crate) port: u16,
+}
+
+impl DiscoveryConfig {
+    pub(crate) fn launch(&self, name: String) -> Result<Discovery, librespot_discovery::Error> {
+        Discovery::builder(self.device_id.clone(), self.client_id.clone())
+            .name(name)
+            .device_type(self.device_type)
+            .is_group(self.is_group)
+            .zeroconf_ip(self.zeroconf_ip.clone())
+            .port(self.port)
+            .launch()
+    }
+}
+
 pub(crate) enum CredentialsProvider {
     Discovery {
         stream: Peekable<Discovery>,
+        config: Box<DiscoveryConfig>,
         last_credentials: Option<Credentials>,
+        access: DiscoveryAccess,
     },
     CredentialsOnly(Credentials),
 }
 
 impl CredentialsProvider {
-    async fn get_credentials(&mut self) -> Credentials {
+    async fn get_credentials(&mut self) -> Result<Credentials, RejectedConnection> {
         match self {
             CredentialsProvider::Discovery {
                 stream,
                 last_credentials,
+                access,
+                ..
             } => {
-                let new_creds = match last_credentials.take() {
-                    Some(creds) => stream.next().now_or_never().flatten().unwrap_or(creds),
-                    None => stream.next().await.unwrap(),
+                let incoming = match last_credentials {
+                    Some(_) => stream.next().now_or_never().flatten(),
+                    None => Some(stream.next().await.unwrap()),
                 };
-                *last_credentials = Some(new_creds.clone());
-                new_creds
+                if let Some(new_creds) = incoming {
+                    access.check(&new_creds)?;
+                    *last_credentials = Some(new_creds);
+                }
+                Ok(last_credentials
+                    .clone()
+                    .expect("credentials have been set above"))
             }
-            CredentialsProvider::CredentialsOnly(creds) => creds.clone(),
+            CredentialsProvider::CredentialsOnly(creds) => Ok(creds.clone()),
+        }
+    }
+
+    /// Replaces the credentials that will be used for the next connection.
+    fn switch_credentials(&mut self, new_creds: Credentials) {
+        match self {
+            CredentialsProvider::Discovery {
+                last_credentials, ..
+            } => *last_credentials = Some(new_creds),
+            CredentialsProvider::CredentialsOnly(creds) => *creds = new_creds,
         }
     }
 
     // wait for an incoming connection if the underlying provider is a discovery stream
-    async fn incoming_connection(&mut self) {
+    //
+    // Connections that are rejected are removed from the stream and returned as error.
+    // Otherwise, the user of the new connection is returned.
+    async fn incoming_connection(&mut self) -> Result<Option<String>, RejectedConnection> {
         match self {
-            CredentialsProvider::Discovery { stream, .. } => {
-                let peeked = Pin::new(stream).peek().await;
-                if peeked.is_none() {
-                    future::pending().await
+            CredentialsProvider::Discovery { stream, access, .. } => {
+                let Some(creds) = Pin::new(&mut *stream).peek().await else {
+                    return future::pending().await;
+                };
+                match access.check(creds) {
+                    Ok(()) => Ok(creds.username.clone()),
+                    Err(rejected) => {
+                        let _ = stream.next().await;
+                        Err(rejected)
+                    }
                 }
             }
             _ => future::pending().await,
         }
     }
+
+    /// Restarts the discovery service, so that the device is advertised with a new name or
+    /// group setting.
+    ///
+    /// If that fails, discovery stays disabled and only the last credentials are used.
+    async fn relaunch_discovery(self, name: String, is_group: bool) -> Self {
+        let CredentialsProvider::Discovery {
+            stream,
+            mut config,
+            last_credentials,
+            access,
+        } = self
+        else {
+            return self;
+        };
+        config.is_group = is_group;
+        stream.into_inner().shutdown().await;
+        match config.launch(name) {
+            Ok(discovery) => CredentialsProvider::Discovery {
+                stream: discovery.peekable(),
+                config,
+                last_credentials,
+                access,
+            },
+            Err(err) => {
+                error!("failed to restart discovery: {err}");
+                CredentialsProvider::CredentialsOnly(
+                    last_credentials.expect("there is an active session"),
+                )
+            }
+        }
+    }
+
+    /// Removes an incoming connection from the discovery stream, without connecting.
+    fn take_incoming(&mut self) -> Option<Credentials> {
+        match self {
+            CredentialsProvider::Discovery { stream, .. } => stream.next().now_or_never().flatten(),
+            _ => None,
+        }
+    }
+}
+
+/// Decides whether a discovery login of `incoming_user` may replace the session of
+/// `current_user`, given how long nothing has been playing.
+fn takeover_allowed(
+    policy: TakeoverPolicy,
+    idle_timeout: Duration,
+    incoming_user: Option<&str>,
+    current_user: &str,
+    idle_for: Option<Duration>,
+) -> bool {
+    let same_user = incoming_user.is_some_and(|user| user.eq_ignore_ascii_case(current_user));
+    match policy {
+        TakeoverPolicy::Always => true,
+        TakeoverPolicy::SameUser => same_user,
+        TakeoverPolicy::Idle => same_user || idle_for.is_some_and(|idle| idle >= idle_timeout),
+        TakeoverPolicy::NotWhilePlaying => same_user || idle_for.is_some(),
+    }
+}
+
+/// Creates the audio sink for a player, from the audio device and format.
+pub(crate) type SinkBuilder =
+    Arc<dyn Fn(Option<String>, AudioFormat) -> Box<dyn Sink> + Send + Sync>;
+
+/// An audio output, i.e. a backend with its device and format.
+#[derive(Clone)]
+pub(crate) struct AudioOutput {
+    /// A description for the logs, e.g. `alsa (hw:1)`.
+    pub(crate) name: String,
+    pub(crate) builder: SinkBuilder,
+    pub(crate) device: Option<String>,
+    pub(crate) format: AudioFormat,
+}
+
+impl AudioOutput {
+    pub(crate) fn open(&self) -> Box<dyn Sink> {
+        (self.builder)(self.device.clone(), self.format)
+    }
 }
 
 pub(crate) struct MainLoop {
@@ -77,7 +360,25 @@ pub(crate) struct MainLoop {
     pub(crate) player_config: PlayerConfig,
     pub(crate) cache: Option<Cache>,
     pub(crate) mixer: Arc<dyn Mixer>,
-    pub(crate) backend: fn(Option<String>, AudioFormat) -> Box<dyn Sink>,
+    /// The outermost mixer, which remembers whether it is muted across connections.
+    pub(crate) mute: Arc<MuteMixer>,
+    pub(crate) volume_memory: Option<Arc<VolumeMemory>>,
+    pub(crate) backend: SinkBuilder,
+    /// The network streams, if the audio is streamed over the network.
+    pub(crate) stream_outputs: Vec<StreamOutput>,
+    pub(crate) output_monitor: Arc<OutputMonitor>,
+    #[cfg(feature = "dbus_mpris")]
+    pub(crate) equalizer: Option<Arc<Equalizer>>,
+    /// The crossfade, if it has been enabled in the config.
+    pub(crate) crossfade: Option<Arc<Crossfade>>,
+    pub(crate) fader: Option<Arc<Fader>>,
+    /// The ducker, if ducking is possible via signals or D-Bus.
+    pub(crate) ducker: Option<Arc<Ducker>>,
+    /// Volumes set outside of spotifyd, which are pushed to Spotify.
+    pub(crate) volume_changes: tokio::sync::mpsc::UnboundedReceiver<u16>,
+    pub(crate) volume_steps: u16,
+    /// The volume of the mixer at full volume in Spotify.
+    pub(crate) max_volume: u16,
     pub(crate) audio_device: Option<String>,
     pub(crate) audio_format: AudioFormat,
     pub(crate) disable_volume: bool,
@@ -85,15 +386,32 @@ pub(crate) struct MainLoop {
     pub(crate) shell: String,
     pub(crate) device_type: DeviceType,
     pub(crate) device_name: String,
+    /// Where a name set at runtime is kept across restarts.
+    pub(crate) stored_name: Option<StoredName>,
+    pub(crate) is_group: bool,
     pub(crate) player_event_program: Option<String>,
     pub(crate) credentials_provider: CredentialsProvider,
+    pub(crate) accounts: Option<Accounts>,
+    pub(crate) active_account: Option<String>,
+    pub(crate) takeover_policy: TakeoverPolicy,
+    pub(crate) takeover_idle_timeout: Duration,
+    pub(crate) devices: DeviceRegistry,
+    pub(crate) device_index: usize,
+    #[cfg(unix)]
+    pub(crate) snapcast_socket: Option<PathBuf>,
+    /// The attenuation and duration of ducking on `SIGUSR1`, if enabled.
+    #[cfg(unix)]
+    pub(crate) duck_db: Option<f64>,
+    #[cfg(unix)]
+    pub(crate) duck_duration: Option<Duration>,
     #[cfg(feature = "dbus_mpris")]
     pub(crate) mpris_config: MprisConfig,
+    #[cfg(feature = "dbus_mpris")]
+    pub(crate) bus_name: Option<String>,
 }
 
 struct ConnectionInfo<SpircTask: Future<Output = ()>> {
     spirc: Spirc,
-    #[cfg_attr(not(feature = "dbus_mpris"), expect(unused))]
     session: Session,
     player: Arc<Player>,
     spirc_task: SpircTask,
@@ -103,7 +421,12 @@ impl MainLoop {
     async fn get_connection(
         &mut self,
     ) -> Result<ConnectionInfo<impl Future<Output = ()> + use<>>, Error> {
-        let creds = self.credentials_provider.get_credentials().await;
+        let creds = loop {
+            match self.credentials_provider.get_credentials().await {
+                Ok(creds) => break creds,
+                Err(rejected) => self.report_rejected(rejected),
+            }
+        };
 
         let mut connection_backoff = Backoff::default();
         loop {
@@ -111,7 +434,7 @@ impl MainLoop {
             let player = {
                 let audio_device = self.audio_device.clone();
                 let audio_format = self.audio_format;
-                let backend = self.backend;
+                let backend = self.backend.clone();
                 Player::new(
                     self.player_config.clone(),
                     session.clone(),
@@ -120,14 +443,19 @@ impl MainLoop {
                 )
             };
 
-            // TODO: expose is_group
             match Spirc::new(
                 ConnectConfig {
                     name: self.device_name.clone(),
                     device_type: self.device_type,
-                    initial_volume: self.initial_volume,
+                    is_group: self.is_group,
+                    // Spotify shows the volume of a muted device as zero
+                    initial_volume: if self.mute.is_muted() {
+                        0
+                    } else {
+                        self.initial_volume
+                    },
                     disable_volume: self.disable_volume,
-                    ..ConnectConfig::default()
+                    volume_steps: self.volume_steps,
                 },
                 session.clone(),
                 creds.clone(),
@@ -137,6 +465,10 @@ impl MainLoop {
             .await
             {
                 Ok((spirc, spirc_task)) => {
+                    // the configured value the session has been started with, since spirc doesn't
+                    // report what it announces
+                    let is_group = self.is_group;
+                    self.update_status(|status| status.is_group = is_group);
                     break Ok(ConnectionInfo {
                         spirc,
                         session,
@@ -161,7 +493,88 @@ impl MainLoop {
         }
     }
 
+    fn update_status(&self, update: impl FnOnce(&mut DeviceStatus)) {
+        let mut devices = self
+            .devices
+            .write()
+            .expect("device registry has been poisoned");
+        update(&mut devices[self.device_index]);
+    }
+
+    pub(crate) fn report_rejected(&self, rejected: RejectedConnection) {
+        let username = rejected.username.unwrap_or_default();
+        warn!(
+            "Rejected discovery login of user '{username}' ({})",
+            rejected.reason
+        );
+        self.run_hook(HookEvent::DiscoveryRejected {
+            username,
+            reason: rejected.reason.to_string(),
+        });
+    }
+
+    /// Updates the status and informs the hook about a change of the sleep timer.
+    fn report_sleep_timer(&self, action: SleepTimerAction, timer: SleepTimer) {
+        self.update_status(|status| {
+            status.sleep_timer = (action == SleepTimerAction::Set).then_some(timer)
+        });
+        self.run_hook(HookEvent::SleepTimer { action, timer });
+    }
+
+    fn run_hook(&self, event: HookEvent) {
+        let Some(ref cmd) = self.player_event_program else {
+            return;
+        };
+        match spawn_program_on_hook_event(&self.shell, cmd, event) {
+            Ok(child) => {
+                tokio::spawn(async move {
+                    if let Err(e) = child.wait().await {
+                        error!("{}", e);
+                    }
+                });
+            }
+            Err(e) => error!("{}", e),
+        }
+    }
+
+    async fn relaunch_discovery(&mut self) {
+        let provider = std::mem::replace(
+            &mut self.credentials_provider,
+            CredentialsProvider::CredentialsOnly(Credentials::default()),
+        );
+        self.credentials_provider = provider
+            .relaunch_discovery(self.device_name.clone(), self.is_group)
+            .await;
+    }
+
+    /// Prepares switching to another account and returns whether a reconnect is needed.
+    ///
+    /// The default account is always switched to, since the active credentials might also be
+    /// those of a discovery login.
+    fn switch_account(&mut self, label: Option<String>) -> bool {
+        let name = label.as_deref().unwrap_or("default");
+        if label.is_some() && self.active_account == label {
+            info!("Account '{name}' is already active");
+            return false;
+        }
+        let Some(creds) = self
+            .accounts
+            .as_ref()
+            .and_then(|accounts| accounts.credentials(label.as_deref()))
+        else {
+            warn!("Cannot switch to account '{name}', because no credentials are stored for it");
+            return false;
+        };
+        info!("Switching to account '{name}'");
+        self.credentials_provider.switch_credentials(creds);
+        self.active_account = label;
+        true
+    }
+
     pub(crate) async fn run(mut self) -> eyre::Result<()> {
+        #[cfg_attr(not(feature = "dbus_mpris"), expect(unused_variables))]
+        let (command_tx, mut command_rx) = tokio::sync::mpsc::unbounded_channel();
+
         tokio::pin! {
             let ctrl_c = tokio::signal::ctrl_c();
             // we don't necessarily have a dbus server
@@ -174,12 +587,46 @@ impl MainLoop {
             *dbus_server.as_mut() = Either::Left(DbusServer::new(
                 rx,
                 self.mpris_config.dbus_type.unwrap_or(DBusType::Session),
+                DbusContext {
+                    command_tx: command_tx.clone(),
+                    accounts: self.accounts.clone(),
+                    bus_name: self.bus_name.clone(),
+                    devices: self.devices.clone(),
+                    device_index: self.device_index,
+                    device_id: self.session_config.device_id.clone(),
+                    equalizer: self.equalizer.clone(),
+                    crossfade: self.crossfade.clone(),
+                    mute: self.mute.clone(),
+                    ducker: self.ducker.clone(),
+                },
             ));
             Some(tx)
         } else {
             None
         };
 
+        #[cfg(unix)]
+        let snapcast = match &self.snapcast_socket {
+            Some(path) => Some(
+                SnapcastServer::bind(path, self.mute.clone())
+                    .wrap_err_with(|| format!("failed to open Snapcast socket {path:?}"))?,
+            ),
+            None => None,
+        };
+        #[cfg(unix)]
+        if let (Some(ducker), Some(db)) = (&self.ducker, self.duck_db) {
+            duck::handle_signals(ducker.clone(), db, self.duck_duration)
+                .wrap_err("failed to listen for the ducking signals")?;
+        }
+
+        // kept across connections, like the fade-out it controls
+        let mut sleep_timer: Option<SleepTimer> = None;
+        // whether the sleep timer has faded out the audio, which is restored on the next play
+        let mut sleep_faded = false;
+        // the length of the current track, and when it ends if it is playing
+        let mut track_duration: Option<Duration> = None;
+        let mut track_end: Option<Instant> = None;
+
         let mainloop_result: eyre::Result<()> = 'mainloop: loop {
             let connection = tokio::select!(
                 _ = &mut ctrl_c => {
@@ -196,13 +643,44 @@ impl MainLoop {
             let spirc_task = connection.spirc_task;
             tokio::pin!(spirc_task);
 
+            // ends along with the player
+            if let Some(crossfade) = &self.crossfade {
+                tokio::spawn(crossfade.clone().watch(
+                    connection.player.get_player_event_channel(),
+                    connection.session.clone(),
+                ));
+            }
+            if let Some(fader) = &self.fader {
+                fader.follow(connection.player.get_player_event_channel());
+            }
+
+            let username = connection.session.username();
+            self.update_status(|status| status.username = Some(username.clone()));
+            // the time since which nothing is playing, or None while playing
+            let mut idle_since = Some(Instant::now());
+            let mut pending_takeover: Option<Credentials> = None;
+
             let shared_spirc = Arc::new(connection.spirc);
+            if let Some(volume_memory) = &self.volume_memory {
+                tokio::spawn(volume_memory.clone().watch(
+                    connection.player.get_player_event_channel(),
+                    shared_spirc.clone(),
+                    self.mute.clone(),
+                ));
+            }
+
+            #[cfg(unix)]
+            if let Some(snapcast) = &snapcast {
+                snapcast.set_spirc(Some(shared_spirc.clone()));
+            }
 
             #[cfg(feature = "dbus_mpris")]
             if let Either::Left(mut dbus_server) = Either::as_pin_mut(dbus_server.as_mut())
-                && let Err(err) = dbus_server
-                    .as_mut()
-                    .set_session(shared_spirc.clone(), connection.session)
+                && let Err(err) = dbus_server.as_mut().set_session(
+                    shared_spirc.clone(),
+                    connection.session,
+                    self.active_account.clone(),
+                )
             {
                 let _ = shared_spirc.shutdown();
                 let _ = (&mut spirc_task).await;
@@ -214,13 +692,195 @@ impl MainLoop {
             let mut event_channel = connection.player.get_player_event_channel();
 
             loop {
+                let sleep_end = match sleep_timer {
+                    Some(SleepTimer::At(at)) => Some(at),
+                    Some(SleepTimer::EndOfTrack) => track_end,
+                    None => None,
+                };
+                // fade out before the end, then pause at it, or on the end of the track
+                let sleep_wakeup = match sleep_timer {
+                    _ if !sleep_faded => {
+                        sleep_end.map(|end| end.checked_sub(SLEEP_FADE).unwrap_or(end))
+                    }
+                    Some(SleepTimer::At(at)) => Some(at),
+                    _ => None,
+                };
+                // a deferred login is applied once the policy allows it, e.g. after pausing
+                let takeover_wakeup = pending_takeover.as_ref().and_then(|creds| {
+                    if takeover_allowed(
+                        self.takeover_policy,
+                        self.takeover_idle_timeout,
+                        creds.username.as_deref(),
+                        &username,
+                        idle_since.map(|since| since.elapsed()),
+                    ) {
+                        Some(Instant::now())
+                    } else if self.takeover_policy == TakeoverPolicy::Idle {
+                        idle_since.map(|since| since + self.takeover_idle_timeout)
+                    } else {
+                        None
+                    }
+                });
                 tokio::select!(
                     // a new session has been started via the discovery stream
-                    _ = self.credentials_provider.incoming_connection() => {
+                    incoming = self.credentials_provider.incoming_connection() => {
+                        let incoming_user = match incoming {
+                            Ok(incoming_user) => incoming_user,
+                            Err(rejected) => {
+                                self.report_rejected(rejected);
+                                continue;
+                            }
+                        };
+                        if !takeover_allowed(
+                            self.takeover_policy,
+                            self.takeover_idle_timeout,
+                            incoming_user.as_deref(),
+                            &username,
+                            idle_since.map(|since| since.elapsed()),
+                        ) {
+                            let incoming_user = incoming_user.unwrap_or_default();
+                            info!(
+                                "Deferring discovery login of user '{incoming_user}' because of the takeover policy {:?}",
+                                self.takeover_policy
+                            );
+                            pending_takeover = self.credentials_provider.take_incoming();
+                            self.update_status(|status| status.pending_takeover = Some(incoming_user));
+                            continue;
+                        }
+                        self.active_account = None;
                         let _ = shared_spirc.shutdown();
                         let _ = (&mut spirc_task).await;
                         break;
                     }
+                    // a control interface requested a change
+                    Some(command) = command_rx.recv() => {
+                        match command {
+                            MainLoopCommand::SwitchAccount(label) => {
+                                if self.switch_account(label) {
+                                    let _ = shared_spirc.shutdown();
+                                    let _ = (&mut spirc_task).await;
+                                    break;
+                                }
+                            }
+                            MainLoopCommand::ForceTakeover => {
+                                let Some(creds) = pending_takeover.take() else {
+                                    warn!("There is no pending discovery login to take over");
+                                    continue;
+                                };
+                                info!(
+                                    "Forcing takeover by user '{}'",
+                                    creds.username.as_deref().unwrap_or_default()
+                                );
+                                self.credentials_provider.switch_credentials(creds);
+                                self.active_account = None;
+                                let _ = shared_spirc.shutdown();
+                                let _ = (&mut spirc_task).await;
+                                break;
+                            }
+                            MainLoopCommand::Rename(name) => {
+                                info!("Renaming device '{}' to '{name}'", self.device_name);
+                                if let Some(stored_name) = &self.stored_name {
+                                    stored_name.store(&name);
+                                }
+                                self.device_name = name.clone();
+                                self.update_status(|status| status.name = name);
+                                self.relaunch_discovery().await;
+                                // the name is part of the connect state, so reconnect
+                                let _ = shared_spirc.shutdown();
+                                let _ = (&mut spirc_task).await;
+                                break;
+                            }
+                            MainLoopCommand::SetGroup(is_group) => {
+                                if is_group == self.is_group {
+                                    continue;
+                                }
+                                info!(
+                                    "{} the device as speaker group",
+                                    if is_group { "Announcing" } else { "No longer announcing" }
+                                );
+                                self.is_group = is_group;
+                                self.relaunch_discovery().await;
+                                let _ = shared_spirc.shutdown();
+                                let _ = (&mut spirc_task).await;
+                                break;
+                            }
+                            MainLoopCommand::SetSleepTimer(timer) => {
+                                if sleep_faded {
+                                    if let Some(ducker) = &self.ducker {
+                                        ducker.fade_in();
+                                    }
+                                    sleep_faded = false;
+                                }
+                                match timer {
+                                    Some(timer) => {
+                                        match timer.remaining() {
+                                            Some(remaining) => info!(
+                                                "Pausing playback in {} minutes",
+                                                remaining.as_secs().div_ceil(60)
+                                            ),
+                                            None => info!("Pausing playback after the current track"),
+                                        }
+                                        self.report_sleep_timer(SleepTimerAction::Set, timer);
+                                    }
+                                    None => {
+                                        let Some(timer) = sleep_timer else {
+                                            continue;
+                                        };
+                                        info!("Cancelling the sleep timer");
+                                        self.report_sleep_timer(SleepTimerAction::Cancelled, timer);
+                                    }
+                                }
+                                sleep_timer = timer;
+                            }
+                            MainLoopCommand::SetTakeoverPolicy(policy) => {
+                                info!("Changing the takeover policy to {policy:?}");
+                                self.takeover_policy = policy;
+                                self.update_status(|status| status.takeover_policy = policy);
+                            }
+                        }
+                    }
+                    // the takeover policy allows the deferred discovery login now
+                    _ = sleep_until(takeover_wakeup) => {
+                        let Some(creds) = pending_takeover.take() else {
+                            continue;
+                        };
+                        info!(
+                            "Applying the deferred discovery login of user '{}'",
+                            creds.username.as_deref().unwrap_or_default()
+                        );
+                        self.credentials_provider.switch_credentials(creds);
+                        self.active_account = None;
+                        let _ = shared_spirc.shutdown();
+                        let _ = (&mut spirc_task).await;
+                        break;
+                    }
+                    // an audio output is available again, after playback was paused without one
+                    _ = self.output_monitor.resume.notified() => {
+                        info!("Resuming playback");
+                        let _ = shared_spirc.play();
+                    }
+                    // the sleep timer starts to fade out, or has run out
+                    _ = sleep_until(sleep_wakeup) => {
+                        let Some(timer) = sleep_timer else {
+                            continue;
+                        };
+                        if !sleep_faded {
+                            let fade = sleep_end.map(|end| end.saturating_duration_since(Instant::now()));
+                            if let Some(ducker) = &self.ducker {
+                                ducker.fade_out(fade.unwrap_or_default());
+                            }
+                            sleep_faded = true;
+                            continue;
+                        }
+                        info!("Pausing playback, since the sleep timer has run out");
+                        let _ = shared_spirc.pause();
+                        sleep_timer = None;
+                        self.report_sleep_timer(SleepTimerAction::Expired, timer);
+                    }
+                    // the volume has been changed outside of spotifyd
+                    Some(volume) = self.volume_changes.recv() => {
+                        let _ = shared_spirc.set_volume(volume_limit::unlimit(volume, self.max_volume));
+                    }
                     // the program should shut down
                     _ = &mut ctrl_c => {
                         let _ = shared_spirc.shutdown();
@@ -246,6 +906,56 @@ impl MainLoop {
                     // a new player event is available and no program is running
                     event = event_channel.recv(), if running_event_program.is_terminated() => {
                         let event = event.unwrap();
+                        match &event {
+                            PlayerEvent::Playing { position_ms, .. } => {
+                                // a timer for the end of the track fades out again before it
+                                if sleep_faded && !matches!(sleep_timer, Some(SleepTimer::At(_))) {
+                                    if let Some(ducker) = &self.ducker {
+                                        ducker.fade_in();
+                                    }
+                                    sleep_faded = false;
+                                }
+                                track_end = end_of_track(track_duration, *position_ms);
+                                idle_since = None;
+                                self.update_status(|status| status.playing = true)
+                            }
+                            PlayerEvent::Seeked { position_ms, .. }
+                            | PlayerEvent::PositionCorrection { position_ms, .. }
+                                if track_end.is_some() =>
+                            {
+                                if sleep_faded && sleep_timer == Some(SleepTimer::EndOfTrack) {
+                                    if let Some(ducker) = &self.ducker {
+                                        ducker.fade_in();
+                                    }
+                                    sleep_faded = false;
+                                }
+                                track_end = end_of_track(track_duration, *position_ms);
+                            }
+                            PlayerEvent::Paused { .. } | PlayerEvent::Stopped { .. } => {
+                                track_end = None;
+                                idle_since.get_or_insert_with(Instant::now);
+                                self.update_status(|status| status.playing = false)
+                            }
+                            PlayerEvent::EndOfTrack { .. }
+                                if sleep_timer == Some(SleepTimer::EndOfTrack) =>
+                            {
+                                info!("Pausing playback at the end of the track");
+                                let _ = shared_spirc.pause();
+                                sleep_timer = None;
+                                self.report_sleep_timer(SleepTimerAction::Expired, SleepTimer::EndOfTrack);
+                            }
+                            PlayerEvent::TrackChanged { audio_item } => {
+                                track_duration = Some(Duration::from_millis(audio_item.duration_ms.into()));
+                                for output in &self.stream_outputs {
+                                    output.set_track(audio_item);
+                                }
+                            }
+                            _ => (),
+                        }
+                        #[cfg(unix)]
+                        if let Some(snapcast) = &snapcast {
+                            snapcast.handle_event(&event);
+                        }
                         #[cfg(feature = "dbus_mpris")]
                         if let Some(ref tx) = mpris_event_tx {
                             tx.send(event.clone()).unwrap();
@@ -268,6 +978,16 @@ impl MainLoop {
                     }
                 )
             }
+            self.update_status(|status| {
+                status.username = None;
+                status.playing = false;
+                status.pending_takeover = None;
+            });
+            #[cfg(unix)]
+            if let Some(snapcast) = &snapcast {
+                snapcast.set_spirc(None);
+            }
+
             #[cfg(feature = "dbus_mpris")]
             if let Either::Left(dbus_server) = Either::as_pin_mut(dbus_server.as_mut())
                 && let Err(err) = dbus_server.drop_session()
@@ -294,3 +1014,61 @@ impl MainLoop {
         mainloop_result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_discovery_access() {
+        let creds = |name: &str| Credentials::with_password(name, "");
+        let rejection = |access: &DiscoveryAccess, name: &str| {
+            access
+                .check(&creds(name))
+                .err()
+                .map(|rejected| rejected.reason)
+        };
+
+        let open = DiscoveryAccess::default();
+        assert_eq!(rejection(&open, "alice"), None);
+
+        let access = DiscoveryAccess {
+            allowed_users: vec!["alice".to_string(), "bob".to_string()],
+            denied_users: vec!["bob".to_string()],
+            owner: None,
+        };
+        assert_eq!(rejection(&access, "Alice"), None);
+        assert_eq!(rejection(&access, "bob"), Some("denied"));
+        assert_eq!(rejection(&access, "eve"), Some("not allowed"));
+
+        let owner_only = DiscoveryAccess {
+            owner: Some("carol".to_string()),
+            ..access
+        };
+        assert_eq!(rejection(&owner_only, "carol"), None);
+        assert_eq!(rejection(&owner_only, "alice"), Some("not the owner"));
+    }
+
+    #[test]
+    fn test_takeover_policy() {
+        let timeout = Duration::from_secs(300);
+        let playing = None;
+        let paused_now = Some(Duration::ZERO);
+        let paused_long_ago = Some(Duration::from_secs(600));
+        let allowed = |policy, user, idle_for| {
+            takeover_allowed(policy, timeout, Some(user), "alice", idle_for)
+        };
+
+        assert!(allowed(TakeoverPolicy::Always, "bob", playing));
+
+        assert!(allowed(TakeoverPolicy::SameUser, "Alice", playing));
+        assert!(!allowed(TakeoverPolicy::SameUser, "bob", paused_now));
+
+        assert!(!allowed(TakeoverPolicy::NotWhilePlaying, "bob", playing));
+        assert!(allowed(TakeoverPolicy::NotWhilePlaying, "bob", paused_now));
+        assert!(allowed(TakeoverPolicy::NotWhilePlaying, "alice", playing));
+
+        assert!(!allowed(TakeoverPolicy::Idle, "bob", paused_now));
+        assert!(allowed(TakeoverPolicy::Idle, "bob", paused_long_ago));
+    }
+}
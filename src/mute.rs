@@ -0,0 +1,152 @@
+//! Muting, with the playback switch of the mixer or by remembering the volume.
+//!
+//! While muted, the volume is shown as zero in Spotify, and choosing another volume there
+//! unmutes. The mixer is kept across connections, so muting survives reconnects.
+
+use librespot_connect::Spirc;
+use librespot_playback::mixer::{Mixer, MixerConfig, VolumeGetter};
+use log::info;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Turns the playback switch of a mixer off (`true`) or on (`false`).
+pub(crate) type MuteSwitch = Box<dyn Fn(bool) + Send + Sync>;
+
+#[derive(Default)]
+struct State {
+    muted: bool,
+    /// The volume to return to, when unmuting without a playback switch.
+    volume: u16,
+}
+
+/// A mixer that can be muted, wrapping another mixer.
+pub(crate) struct MuteMixer {
+    mixer: Arc<dyn Mixer>,
+    switch: Option<MuteSwitch>,
+    state: Mutex<State>,
+}
+
+impl MuteMixer {
+    pub(crate) fn new(mixer: Arc<dyn Mixer>, switch: Option<MuteSwitch>) -> Self {
+        Self {
+            mixer,
+            switch,
+            state: Mutex::default(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("mute mixer has been poisoned")
+    }
+
+    pub(crate) fn is_muted(&self) -> bool {
+        self.state().muted
+    }
+
+    /// Mutes or unmutes, and updates the volume shown in Spotify, if connected.
+    #[cfg_attr(not(any(feature = "dbus_mpris", unix)), expect(dead_code))]
+    pub(crate) fn set_muted(
+        &self,
+        muted: bool,
+        spirc: Option<&Spirc>,
+    ) -> Result<(), librespot_core::Error> {
+        let volume = {
+            let mut state = self.state();
+            if state.muted == muted {
+                return Ok(());
+            }
+            state.muted = muted;
+            if muted {
+                info!("Muting");
+                state.volume = self.mixer.volume();
+                match &self.switch {
+                    Some(switch) => switch(true),
+                    None => self.mixer.set_volume(0),
+                }
+                0
+            } else {
+                info!("Unmuting");
+                if let Some(switch) = &self.switch {
+                    switch(false);
+                }
+                self.mixer.set_volume(state.volume);
+                state.volume
+            }
+        };
+        match spirc {
+            Some(spirc) => spirc.set_volume(volume),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Mixer for MuteMixer {
+    fn open(_: MixerConfig) -> Result<Self, librespot_core::Error> {
+        Err(librespot_core::Error::unimplemented(
+            "a mute mixer wraps another mixer",
+        ))
+    }
+
+    fn volume(&self) -> u16 {
+        if self.is_muted() {
+            0
+        } else {
+            self.mixer.volume()
+        }
+    }
+
+    fn set_volume(&self, volume: u16) {
+        let mut state = self.state();
+        if state.muted {
+            // the muted volume, as shown in Spotify
+            if volume == 0 {
+                return;
+            }
+            info!("Unmuting, since the volume has been changed");
+            state.muted = false;
+            if let Some(switch) = &self.switch {
+                switch(false);
+            }
+        }
+        self.mixer.set_volume(volume);
+    }
+
+    fn get_soft_volume(&self) -> Box<dyn VolumeGetter + Send> {
+        self.mixer.get_soft_volume()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use librespot_playback::{config::VolumeCtrl, mixer::softmixer::SoftMixer};
+
+    #[test]
+    fn test_soft_mute() {
+        let soft_mixer = Arc::new(
+            SoftMixer::open(MixerConfig {
+                volume_ctrl: VolumeCtrl::Linear,
+                ..MixerConfig::default()
+            })
+            .unwrap(),
+        );
+        let mixer = MuteMixer::new(soft_mixer.clone(), None);
+        mixer.set_volume(40000);
+
+        mixer.set_muted(true, None).unwrap();
+        assert!(mixer.is_muted());
+        assert_eq!(mixer.volume(), 0);
+        assert_eq!(soft_mixer.volume(), 0);
+        // Spotify showing the muted volume doesn't unmute
+        mixer.set_volume(0);
+        assert!(mixer.is_muted());
+
+        mixer.set_muted(false, None).unwrap();
+        assert!(mixer.volume().abs_diff(40000) <= 1);
+
+        // choosing a volume in Spotify unmutes
+        mixer.set_muted(true, None).unwrap();
+        mixer.set_volume(20000);
+        assert!(!mixer.is_muted());
+        assert!(soft_mixer.volume().abs_diff(20000) <= 1);
+    }
+}
@@ -5,12 +5,75 @@ use color_eyre::{
 use librespot_core::SessionConfig;
 use librespot_core::{Session, authentication::Credentials};
 use librespot_oauth::OAuthClientBuilder;
-use log::info;
+use log::{info, warn};
+use std::{
+    io::{self, BufRead as _, Read as _, Write as _},
+    net::{SocketAddr, TcpStream, ToSocketAddrs as _},
+    thread,
+};
 use tokio::runtime::Runtime;
+use url::Url;
+
+use crate::{
+    LogTarget,
+    config::{AuthenticateArgs, CliConfig},
+    setup_logger,
+};
 
-use crate::{LogTarget, config::CliConfig, setup_logger};
+/// Extracts the authorization code from a pasted redirect URL or takes the input as the code itself.
+fn parse_pasted_code(input: &str) -> Option<String> {
+    let input = input.trim();
+    if input.is_empty() {
+        return None;
+    }
+    match Url::parse(input) {
+        Ok(url) => url
+            .query_pairs()
+            .find(|(key, _)| key == "code")
+            .map(|(_, code)| code.into_owned()),
+        Err(_) => Some(input.to_string()),
+    }
+}
+
+/// Reads the redirect URL or code from stdin and hands it to the waiting redirect server.
+///
+/// This way, the login can be finished on another device, even if its browser
+/// can't reach the redirect server.
+fn forward_pasted_code(server_addr: SocketAddr) {
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            let Some(code) = parse_pasted_code(&line) else {
+                warn!(
+                    "Couldn't find an authorization code, please paste the complete redirect URL"
+                );
+                continue;
+            };
+            let code: String = url::form_urlencoded::byte_serialize(code.as_bytes()).collect();
+            let request = format!("GET /login?code={code} HTTP/1.1\r\nHost: {server_addr}\r\n\r\n");
+            // wait for the response, so that the server doesn't write to a closed connection
+            match TcpStream::connect(server_addr).and_then(|mut stream| {
+                stream.write_all(request.as_bytes())?;
+                stream.read_to_end(&mut Vec::new())
+            }) {
+                Ok(_) => break,
+                Err(err) => warn!("Failed to hand over the authorization code: {err}"),
+            }
+        }
+    });
+}
+
+pub(crate) fn run_oauth(mut cli_config: CliConfig, args: AuthenticateArgs) -> eyre::Result<()> {
+    let AuthenticateArgs {
+        oauth_port,
+        redirect_host,
+        headless,
+        account,
+        action: _,
+    } = args;
 
-pub(crate) fn run_oauth(mut cli_config: CliConfig, oauth_port: u16) -> eyre::Result<()> {
     setup_logger(LogTarget::Terminal, cli_config.verbose)?;
 
     cli_config
@@ -19,7 +82,8 @@ pub(crate) fn run_oauth(mut cli_config: CliConfig, oauth_port: u16) -> eyre::Res
 
     let cache = cli_config
         .shared_config
-        .get_cache(true)
+        .get_accounts()
+        .and_then(|accounts| accounts.cache(account.as_deref()))
         .with_note(|| "The result of the authentication needs to be cached to be usable later.")?;
 
     const OAUTH_SCOPES: &[&str] = &[
@@ -56,15 +120,35 @@ pub(crate) fn run_oauth(mut cli_config: CliConfig, oauth_port: u16) -> eyre::Res
         ..Default::default()
     };
 
-    let oauth_client = OAuthClientBuilder::new(
+    let redirect_uri = format!("http://{redirect_host}:{oauth_port}/login");
+    let server_addr = (redirect_host.as_str(), oauth_port)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| eyre::eyre!("failed to resolve redirect host '{redirect_host}'"))?;
+    if !server_addr.ip().is_loopback() {
+        warn!(
+            "Spotify only redirects to {redirect_uri} if it is registered for the client ID. If the login fails, use `--headless` instead."
+        );
+    }
+
+    let mut oauth_client_builder = OAuthClientBuilder::new(
         &session_config.client_id,
-        &format!("http://127.0.0.1:{oauth_port}/login"),
+        &redirect_uri,
         OAUTH_SCOPES.to_vec(),
     )
-    .with_custom_message(r#"<h3 style="color: darkgreen; align: center;">Authentication successful! You can now return to spotifyd.</h3>"#)
-    .open_in_browser()
-    .build()
-    .wrap_err("client creation failed")?;
+    .with_custom_message(r#"<h3 style="color: darkgreen; align: center;">Authentication successful! You can now return to spotifyd.</h3>"#);
+    if headless {
+        info!(
+            "Open the following link on any device and log in. Afterwards, paste the address of the page you are redirected to (even if it fails to load) or the code it contains here."
+        );
+        forward_pasted_code(server_addr);
+    } else {
+        oauth_client_builder = oauth_client_builder.open_in_browser();
+    }
+    let oauth_client = oauth_client_builder
+        .build()
+        .wrap_err("client creation failed")?;
 
     Runtime::new().unwrap().block_on(async move {
         let token = oauth_client.get_access_token_async().await?;
@@ -74,7 +158,31 @@ pub(crate) fn run_oauth(mut cli_config: CliConfig, oauth_port: u16) -> eyre::Res
         session.connect(creds, true).await
     })?;
 
-    info!("\nLogin successful! You are now ready to run spotifyd.");
+    match account {
+        Some(label) => info!(
+            "\nLogin successful! Use `account = \"{label}\"` to log in with this account by default."
+        ),
+        None => info!("\nLogin successful! You are now ready to run spotifyd."),
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pasted_code_parsing() {
+        assert_eq!(
+            parse_pasted_code("http://127.0.0.1:8000/login?code=AQB-x_1&state=abc\n").as_deref(),
+            Some("AQB-x_1")
+        );
+        assert_eq!(parse_pasted_code("  AQB-x_1 ").as_deref(), Some("AQB-x_1"));
+        assert_eq!(
+            parse_pasted_code("http://127.0.0.1:8000/login?error=access_denied"),
+            None
+        );
+        assert_eq!(parse_pasted_code(""), None);
+    }
+}
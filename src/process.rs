@@ -1,4 +1,5 @@
 use crate::error::Error;
+use crate::main_loop::{SleepTimer, SleepTimerAction};
 use librespot_metadata::audio::AudioItem;
 use librespot_playback::player::PlayerEvent;
 use log::info;
@@ -211,6 +212,47 @@ pub(crate) fn spawn_program_on_event(
     spawn_program(shell, cmd, env)
 }
 
+/// Events that are passed to the hook, but don't originate from the player.
+pub(crate) enum HookEvent {
+    /// A discovery login has been refused because of `allowed_users`, `denied_users` or
+    /// `discovery_owner_only`.
+    DiscoveryRejected { username: String, reason: String },
+    /// The sleep timer has been set, cancelled or has expired.
+    SleepTimer {
+        action: SleepTimerAction,
+        timer: SleepTimer,
+    },
+}
+
+/// Spawns provided command in a subprocess using the provided shell, passing the details
+/// of the `HookEvent` as environment variables.
+pub(crate) fn spawn_program_on_hook_event(
+    shell: &str,
+    cmd: &str,
+    event: HookEvent,
+) -> Result<Child, Error> {
+    let mut env = HashMap::new();
+    match event {
+        HookEvent::DiscoveryRejected { username, reason } => {
+            env.insert("PLAYER_EVENT", "discovery_rejected".to_string());
+            env.insert("USERNAME", username);
+            env.insert("REASON", reason);
+        }
+        HookEvent::SleepTimer { action, timer } => {
+            env.insert("PLAYER_EVENT", "sleep_timer".to_string());
+            env.insert("SLEEP_TIMER", action.to_string());
+            env.insert(
+                "END_OF_TRACK",
+                (timer == SleepTimer::EndOfTrack).to_string(),
+            );
+            if let Some(remaining) = timer.remaining() {
+                env.insert("REMAINING_SECONDS", remaining.as_secs().to_string());
+            }
+        }
+    }
+    spawn_program(shell, cmd, env)
+}
+
 /// Wraps `tokio::process::Child` so that when this `Child` exits:
 /// * successfully: It writes the contents of it's stdout to the stdout of the
 ///   main process.
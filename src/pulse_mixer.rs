@@ -0,0 +1,324 @@
+//! A volume controller for the stream of spotifyd in PulseAudio or PipeWire.
+//!
+//! The Connect volume is tied to the volume of the sink-input that belongs to this device, so
+//! that the volume can be changed in the mixer of the desktop as well. Changes from there are
+//! reported, so that they can be pushed to Spotify.
+//!
+//! The streams of the `pulseaudio` backend are named after their device. Streams of other
+//! backends, e.g. alsa through the sound server, can only be recognized by the process id.
+//!
+//! If the connection to the sound server is lost, e.g. because it has been restarted, it is
+//! reconnected, and the stream gets the Connect volume once it shows up again.
+
+use color_eyre::eyre::{self, eyre};
+use libpulse_binding::{
+    callbacks::ListResult,
+    context::{
+        Context, FlagSet, State,
+        introspect::SinkInputInfo,
+        subscribe::{Facility, InterestMaskSet, Operation},
+    },
+    mainloop::standard::{IterateResult, Mainloop},
+    time::MicroSeconds,
+    volume::{ChannelVolumes, Volume},
+};
+use librespot_playback::mixer::{Mixer, MixerConfig};
+use log::{debug, info, warn};
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    process,
+    rc::Rc,
+    sync::{
+        Arc,
+        atomic::{AtomicU16, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, TryRecvError},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// How many of the volumes set by spotifyd are remembered, to tell them apart from changes made
+/// by others.
+const MAX_APPLIED: usize = 32;
+/// How long to wait for events of the sound server, before looking for volumes to be set.
+const POLL_TIMEOUT: MicroSeconds = MicroSeconds(50_000);
+/// How long to wait before reconnecting to the sound server.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+fn to_pulse(volume: u16) -> Volume {
+    Volume((volume as u64 * Volume::NORMAL.0 as u64 / u16::MAX as u64) as u32)
+}
+
+fn from_pulse(volume: Volume) -> u16 {
+    (volume.0.min(Volume::NORMAL.0) as u64 * u16::MAX as u64 / Volume::NORMAL.0 as u64) as u16
+}
+
+pub(crate) struct PulseMixer {
+    volume: Arc<AtomicU16>,
+    requests: mpsc::Sender<u16>,
+}
+
+impl PulseMixer {
+    /// Connects to the sound server, and reports changes of the volume made there.
+    ///
+    /// With a `stream_name`, only the stream of that name is controlled, otherwise any stream of
+    /// this process.
+    pub(crate) fn open(
+        changes: UnboundedSender<u16>,
+        stream_name: Option<String>,
+    ) -> eyre::Result<Self> {
+        let volume = Arc::new(AtomicU16::new(u16::MAX));
+        let (requests, requested) = mpsc::channel();
+        let (connected_tx, connected) = mpsc::sync_channel(1);
+        let shared_volume = volume.clone();
+        thread::spawn(move || {
+            let mut connection = match Connection::open(stream_name.clone()) {
+                Ok(connection) => connection,
+                Err(err) => {
+                    let _ = connected_tx.send(Err(err));
+                    return;
+                }
+            };
+            let _ = connected_tx.send(Ok(()));
+            loop {
+                match connection.run(&requested, &shared_volume, &changes) {
+                    Ok(()) => return,
+                    Err(err) => warn!("Lost the connection to the sound server: {err}"),
+                }
+                connection = loop {
+                    // the requested volumes are applied once the stream is found again
+                    let retry = Instant::now() + RECONNECT_DELAY;
+                    while let Some(timeout) = retry.checked_duration_since(Instant::now()) {
+                        if let Err(RecvTimeoutError::Disconnected) = requested.recv_timeout(timeout)
+                        {
+                            return;
+                        }
+                    }
+                    match Connection::open(stream_name.clone()) {
+                        Ok(connection) => break connection,
+                        Err(err) => debug!("Failed to reconnect to the sound server: {err}"),
+                    }
+                };
+            }
+        });
+        connected
+            .recv()
+            .map_err(|_| eyre!("the connection to the sound server failed"))??;
+        Ok(Self { volume, requests })
+    }
+}
+
+impl Mixer for PulseMixer {
+    fn open(_: MixerConfig) -> Result<Self, librespot_core::Error> {
+        Err(librespot_core::Error::unimplemented(
+            "the pulseaudio mixer reports volume changes",
+        ))
+    }
+
+    fn volume(&self) -> u16 {
+        self.volume.load(Ordering::Relaxed)
+    }
+
+    fn set_volume(&self, volume: u16) {
+        self.volume.store(volume, Ordering::Relaxed);
+        let _ = self.requests.send(volume);
+    }
+}
+
+/// The sink-input of this process.
+#[derive(Clone)]
+struct Stream {
+    index: u32,
+    volume: ChannelVolumes,
+}
+
+struct Connection {
+    mainloop: Mainloop,
+    context: Context,
+    /// The name of the stream of the device, if it can be told apart by that.
+    stream_name: Option<String>,
+    stream: Rc<RefCell<Option<Stream>>>,
+    /// The sink-inputs that have been added or changed since they have been looked at.
+    dirty: Rc<RefCell<Vec<u32>>>,
+    /// The volumes that have been set, but not yet been reported back by the sound server.
+    applied: VecDeque<Volume>,
+}
+
+impl Connection {
+    fn open(stream_name: Option<String>) -> eyre::Result<Self> {
+        let mut mainloop = Mainloop::new().ok_or_else(|| eyre!("failed to create main loop"))?;
+        let mut context = Context::new(&mainloop, "spotifyd")
+            .ok_or_else(|| eyre!("failed to create sound server context"))?;
+        context.connect(None, FlagSet::NOFLAGS, None)?;
+        loop {
+            match mainloop.iterate(true) {
+                IterateResult::Err(err) => {
+                    return Err(eyre!("failed to connect to the sound server: {err}"));
+                }
+                IterateResult::Quit(_) => {
+                    return Err(eyre!("failed to connect to the sound server"));
+                }
+                IterateResult::Success(_) => (),
+            }
+            match context.get_state() {
+                State::Ready => break,
+                State::Failed | State::Terminated => {
+                    return Err(eyre!("failed to connect to the sound server"));
+                }
+                _ => (),
+            }
+        }
+
+        let dirty = Rc::new(RefCell::new(Vec::new()));
+        let stream = Rc::new(RefCell::new(None));
+        {
+            let dirty = dirty.clone();
+            let stream = stream.clone();
+            context.set_subscribe_callback(Some(Box::new(move |facility, operation, index| {
+                if facility != Some(Facility::SinkInput) {
+                    return;
+                }
+                if operation == Some(Operation::Removed) {
+                    let mut stream = stream.borrow_mut();
+                    if stream
+                        .as_ref()
+                        .is_some_and(|stream: &Stream| stream.index == index)
+                    {
+                        debug!("The audio stream has been removed");
+                        *stream = None;
+                    }
+                } else {
+                    dirty.borrow_mut().push(index);
+                }
+            })));
+        }
+        context.subscribe(InterestMaskSet::SINK_INPUT, |_| ());
+
+        // look for a stream that already exists
+        {
+            let dirty = dirty.clone();
+            context
+                .introspect()
+                .get_sink_input_info_list(move |result| {
+                    if let ListResult::Item(info) = result {
+                        dirty.borrow_mut().push(info.index);
+                    }
+                });
+        }
+        info!("Connected to the sound server");
+
+        Ok(Self {
+            mainloop,
+            context,
+            stream_name,
+            stream,
+            dirty,
+            applied: VecDeque::new(),
+        })
+    }
+
+    /// Follows and sets the volume, until the mixer has been dropped or the connection is lost.
+    fn run(
+        &mut self,
+        requested: &Receiver<u16>,
+        volume: &AtomicU16,
+        changes: &UnboundedSender<u16>,
+    ) -> eyre::Result<()> {
+        let found = Rc::new(RefCell::new(Vec::new()));
+        loop {
+            self.mainloop
+                .prepare(Some(POLL_TIMEOUT))
+                .and_then(|_| self.mainloop.poll())
+                .and_then(|_| self.mainloop.dispatch())?;
+            if matches!(self.context.get_state(), State::Failed | State::Terminated) {
+                return Err(eyre!("the sound server has closed the connection"));
+            }
+
+            for index in self.dirty.borrow_mut().drain(..) {
+                let found = found.clone();
+                let stream_name = self.stream_name.clone();
+                self.context
+                    .introspect()
+                    .get_sink_input_info(index, move |result| {
+                        if let ListResult::Item(info) = result
+                            && is_own(info, stream_name.as_deref())
+                        {
+                            found.borrow_mut().push(Stream {
+                                index: info.index,
+                                volume: info.volume,
+                            });
+                        }
+                    });
+            }
+
+            // the streams of this process, that have been added or changed
+            for found in found.borrow_mut().drain(..) {
+                let current = from_pulse(found.volume.max());
+                let known = self.stream.borrow_mut().replace(found.clone());
+                match known {
+                    Some(known) if known.index == found.index => {
+                        let level = found.volume.max();
+                        // our own changes come back, too
+                        if let Some(pos) = self.applied.iter().position(|applied| *applied == level)
+                        {
+                            self.applied.drain(..=pos);
+                        } else if known.volume.max() != level {
+                            debug!("The volume of the audio stream has been changed to {current}");
+                            // the mixer's volume is updated by Spirc, which tells Spotify
+                            let _ = changes.send(current);
+                        }
+                    }
+                    // a new stream gets the Connect volume
+                    _ => {
+                        debug!("Found the audio stream {}", found.index);
+                        self.set_volume(volume.load(Ordering::Relaxed));
+                    }
+                }
+            }
+
+            let mut request = None;
+            loop {
+                match requested.try_recv() {
+                    Ok(volume) => request = Some(volume),
+                    Err(TryRecvError::Empty) => break,
+                    // the mixer has been dropped
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            }
+            if let Some(request) = request {
+                self.set_volume(request);
+            }
+        }
+    }
+
+    fn set_volume(&mut self, volume: u16) {
+        let mut stream = self.stream.borrow_mut();
+        let Some(stream) = stream.as_mut() else {
+            // it is set once the stream appears
+            return;
+        };
+        let channels = stream.volume.len();
+        stream.volume.set(channels, to_pulse(volume));
+        if self.applied.len() == MAX_APPLIED {
+            self.applied.pop_front();
+        }
+        self.applied.push_back(to_pulse(volume));
+        self.context
+            .introspect()
+            .set_sink_input_volume(stream.index, &stream.volume, None);
+    }
+}
+
+/// Whether the sink-input belongs to this device, i.e. has its name or otherwise is of this
+/// process.
+fn is_own(info: &SinkInputInfo, stream_name: Option<&str>) -> bool {
+    let own_process = info
+        .proplist
+        .get_str("application.process.id")
+        .is_some_and(|pid| pid == process::id().to_string());
+    own_process
+        && stream_name
+            .is_none_or(|name| info.proplist.get_str("media.name").as_deref() == Some(name))
+}
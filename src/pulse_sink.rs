@@ -0,0 +1,127 @@
+//! The `pulseaudio` backend, for PulseAudio or PipeWire (through `pipewire-pulse`).
+//!
+//! Unlike the backend of librespot, which takes the names of its stream from the environment of
+//! the process, every stream is named after its device. That way, the streams of several devices
+//! can be told apart, both in the mixer of the desktop and by the `pulseaudio` volume controller.
+
+use crate::main_loop::SinkBuilder;
+use libpulse_binding::{sample, stream::Direction};
+use libpulse_simple_binding::Simple;
+use librespot_playback::{
+    NUM_CHANNELS, SAMPLE_RATE,
+    audio_backend::{Sink, SinkError, SinkResult},
+    config::AudioFormat,
+    convert::Converter,
+    decoder::AudioPacket,
+};
+use log::{info, warn};
+use std::sync::Arc;
+
+pub(crate) const BACKEND: &str = "pulseaudio";
+/// The application name of the streams.
+const APP_NAME: &str = "spotifyd";
+
+struct PulseSink {
+    sink: Option<Simple>,
+    device: Option<String>,
+    /// The name of the spotifyd device, used as name of the stream.
+    stream_name: String,
+    format: AudioFormat,
+}
+
+/// Creates the sink builder of streams named `stream_name`.
+pub(crate) fn builder(stream_name: String) -> SinkBuilder {
+    Arc::new(move |device, format| {
+        let format = if format == AudioFormat::F64 {
+            warn!("PulseAudio doesn't support F64 output, using F32");
+            AudioFormat::F32
+        } else {
+            format
+        };
+        info!("Using the pulseaudio backend with format {format:?}");
+        Box::new(PulseSink {
+            sink: None,
+            device,
+            stream_name: stream_name.clone(),
+            format,
+        })
+    })
+}
+
+impl Sink for PulseSink {
+    fn start(&mut self) -> SinkResult<()> {
+        if self.sink.is_some() {
+            return Ok(());
+        }
+        let format = match self.format {
+            AudioFormat::F32 => sample::Format::FLOAT32NE,
+            AudioFormat::S32 => sample::Format::S32NE,
+            AudioFormat::S24 => sample::Format::S24_32NE,
+            AudioFormat::S24_3 => sample::Format::S24NE,
+            AudioFormat::S16 => sample::Format::S16NE,
+            AudioFormat::F64 => unreachable!("F64 is replaced when the sink is created"),
+        };
+        let spec = sample::Spec {
+            format,
+            channels: NUM_CHANNELS,
+            rate: SAMPLE_RATE,
+        };
+        let sink = Simple::new(
+            None,
+            APP_NAME,
+            Direction::Playback,
+            self.device.as_deref(),
+            &self.stream_name,
+            &spec,
+            None,
+            None,
+        )
+        .map_err(|err| SinkError::ConnectionRefused(format!("<PulseSink> {err}")))?;
+        self.sink = Some(sink);
+        Ok(())
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        let Some(sink) = self.sink.take() else {
+            return Ok(());
+        };
+        sink.drain()
+            .map_err(|err| SinkError::OnWrite(format!("<PulseSink> failed to drain: {err}")))
+    }
+
+    fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        let sink = self
+            .sink
+            .as_ref()
+            .ok_or_else(|| SinkError::NotConnected("<PulseSink> not started".to_string()))?;
+        let bytes: Vec<u8> = match packet {
+            AudioPacket::Samples(samples) => match self.format {
+                AudioFormat::F32 => to_bytes(converter.f64_to_f32(&samples), f32::to_ne_bytes),
+                AudioFormat::S32 => to_bytes(converter.f64_to_s32(&samples), i32::to_ne_bytes),
+                AudioFormat::S24 => to_bytes(converter.f64_to_s24(&samples), i32::to_ne_bytes),
+                AudioFormat::S24_3 => converter
+                    .f64_to_s24(&samples)
+                    .into_iter()
+                    .flat_map(|sample| {
+                        let bytes = sample.to_ne_bytes();
+                        // the three lower bytes
+                        if cfg!(target_endian = "little") {
+                            [bytes[0], bytes[1], bytes[2]]
+                        } else {
+                            [bytes[1], bytes[2], bytes[3]]
+                        }
+                    })
+                    .collect(),
+                AudioFormat::S16 => to_bytes(converter.f64_to_s16(&samples), i16::to_ne_bytes),
+                AudioFormat::F64 => unreachable!("F64 is replaced when the sink is created"),
+            },
+            AudioPacket::Raw(bytes) => bytes,
+        };
+        sink.write(&bytes)
+            .map_err(|err| SinkError::OnWrite(format!("<PulseSink> {err}")))
+    }
+}
+
+fn to_bytes<T, const N: usize>(samples: Vec<T>, convert: fn(T) -> [u8; N]) -> Vec<u8> {
+    samples.into_iter().flat_map(convert).collect()
+}
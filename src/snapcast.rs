@@ -0,0 +1,477 @@
+//! A control socket speaking the JSON-RPC protocol of Snapcast's stream plugins.
+//!
+//! Snapserver starts a `controlscript` for a stream and talks to it via stdin / stdout.
+//! `spotifyd snapcast-control` is such a script, which forwards everything to the socket
+//! opened by the running daemon.
+
+use crate::mute::MuteMixer;
+use color_eyre::eyre::{self, Context as _};
+use librespot_connect::Spirc;
+use librespot_metadata::audio::{AudioItem, UniqueFields};
+use librespot_playback::player::PlayerEvent;
+use log::{debug, info, warn};
+use serde_json::{Value, json};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Instant,
+};
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::broadcast,
+    task::JoinHandle,
+};
+
+/// The state of the player, as reported to Snapcast.
+struct State {
+    spirc: Option<Arc<Spirc>>,
+    mute: Option<Arc<MuteMixer>>,
+    playback_status: &'static str,
+    loop_status: &'static str,
+    shuffle: bool,
+    volume: u16,
+    position_ms: u32,
+    /// The time of the last position update, if playing.
+    playing_since: Option<Instant>,
+    metadata: Value,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            spirc: None,
+            mute: None,
+            playback_status: "stopped",
+            loop_status: "none",
+            shuffle: false,
+            volume: 0,
+            position_ms: 0,
+            playing_since: None,
+            metadata: json!({}),
+        }
+    }
+}
+
+impl State {
+    fn position_ms(&self) -> u32 {
+        let elapsed = self
+            .playing_since
+            .map_or(0, |since| since.elapsed().as_millis() as u32);
+        self.position_ms.saturating_add(elapsed)
+    }
+
+    fn properties(&self) -> Value {
+        let can_control = self.spirc.is_some();
+        json!({
+            "playbackStatus": self.playback_status,
+            "loopStatus": self.loop_status,
+            "shuffle": self.shuffle,
+            "volume": (self.volume as u32 * 100 + u16::MAX as u32 / 2) / u16::MAX as u32,
+            "mute": self.mute.as_ref().is_some_and(|mute| mute.is_muted()),
+            "rate": 1.0,
+            "position": self.position_ms() as f64 / 1000.0,
+            "canGoNext": can_control,
+            "canGoPrevious": can_control,
+            "canPlay": can_control,
+            "canPause": can_control,
+            "canSeek": can_control,
+            "canControl": can_control,
+            "metadata": self.metadata,
+        })
+    }
+
+    /// Updates the state and returns whether anything has changed.
+    fn handle_event(&mut self, event: &PlayerEvent) -> bool {
+        match event {
+            PlayerEvent::Playing { position_ms, .. } => {
+                self.playback_status = "playing";
+                self.position_ms = *position_ms;
+                self.playing_since = Some(Instant::now());
+            }
+            PlayerEvent::Paused { position_ms, .. } => {
+                self.playback_status = "paused";
+                self.position_ms = *position_ms;
+                self.playing_since = None;
+            }
+            PlayerEvent::Stopped { .. } => {
+                self.playback_status = "stopped";
+                self.position_ms = 0;
+                self.playing_since = None;
+            }
+            PlayerEvent::PositionCorrection { position_ms, .. }
+            | PlayerEvent::PositionChanged { position_ms, .. }
+            | PlayerEvent::Seeked { position_ms, .. } => {
+                self.position_ms = *position_ms;
+                self.playing_since = self.playing_since.map(|_| Instant::now());
+            }
+            PlayerEvent::TrackChanged { audio_item } => self.metadata = metadata(audio_item),
+            PlayerEvent::VolumeChanged { volume } => self.volume = *volume,
+            PlayerEvent::ShuffleChanged { shuffle } => self.shuffle = *shuffle,
+            PlayerEvent::RepeatChanged { context, track } => {
+                self.loop_status = match (context, track) {
+                    (_, true) => "track",
+                    (true, false) => "playlist",
+                    (false, false) => "none",
+                };
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn spirc(&self) -> Result<&Spirc, RpcError> {
+        self.spirc.as_deref().ok_or(RpcError {
+            code: -32000,
+            message: "not connected to Spotify".to_string(),
+        })
+    }
+}
+
+fn metadata(audio_item: &AudioItem) -> Value {
+    let mut metadata = json!({
+        "trackId": audio_item.uri,
+        "title": audio_item.name,
+        "duration": audio_item.duration_ms as f64 / 1000.0,
+    });
+    if let Some(cover) = audio_item.covers.iter().max_by_key(|cover| cover.width) {
+        metadata["artUrl"] = json!(cover.url);
+    }
+    match &audio_item.unique_fields {
+        UniqueFields::Track {
+            artists,
+            album,
+            album_artists,
+            number,
+            disc_number,
+            ..
+        } => {
+            let artists: Vec<&str> = artists.iter().map(|artist| artist.name.as_str()).collect();
+            metadata["artist"] = json!(artists);
+            metadata["album"] = json!(album);
+            metadata["albumArtist"] = json!(album_artists);
+            metadata["trackNumber"] = json!(number);
+            metadata["discNumber"] = json!(disc_number);
+        }
+        UniqueFields::Local { artists, album, .. } => {
+            if let Some(artists) = artists {
+                metadata["artist"] = json!([artists]);
+            }
+            if let Some(album) = album {
+                metadata["album"] = json!(album);
+            }
+        }
+        UniqueFields::Episode { show_name, .. } => {
+            metadata["artist"] = json!([show_name]);
+            metadata["album"] = json!(show_name);
+        }
+    }
+    metadata
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn invalid_params(message: impl Into<String>) -> Self {
+        Self {
+            code: -32602,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<librespot_core::Error> for RpcError {
+    fn from(err: librespot_core::Error) -> Self {
+        Self {
+            code: -32000,
+            message: err.to_string(),
+        }
+    }
+}
+
+fn control(state: &State, params: &Value) -> Result<Value, RpcError> {
+    let spirc = state.spirc()?;
+    let seconds = |name: &str| {
+        params["params"][name]
+            .as_f64()
+            .ok_or_else(|| RpcError::invalid_params(format!("missing parameter '{name}'")))
+    };
+    match params["command"].as_str().unwrap_or_default() {
+        "play" => spirc.play()?,
+        "pause" | "stop" => spirc.pause()?,
+        "playPause" => spirc.play_pause()?,
+        "next" => spirc.next()?,
+        "previous" => spirc.prev()?,
+        "seek" => {
+            let position = state.position_ms() as f64 + seconds("offset")? * 1000.0;
+            spirc.set_position_ms(position.max(0.0) as u32)?
+        }
+        "setPosition" => spirc.set_position_ms((seconds("position")?.max(0.0) * 1000.0) as u32)?,
+        command => {
+            return Err(RpcError::invalid_params(format!(
+                "unknown command '{command}'"
+            )));
+        }
+    }
+    Ok(json!("ok"))
+}
+
+fn set_property(state: &State, params: &Value) -> Result<Value, RpcError> {
+    let spirc = state.spirc()?;
+    let Some(properties) = params.as_object() else {
+        return Err(RpcError::invalid_params("expected an object"));
+    };
+    for (name, value) in properties {
+        match (name.as_str(), value) {
+            ("volume", Value::Number(volume)) => {
+                let volume = volume.as_u64().unwrap_or_default().min(100);
+                spirc.set_volume((volume * u16::MAX as u64 / 100) as u16)?;
+            }
+            ("mute", Value::Bool(muted)) => match &state.mute {
+                Some(mute) => mute.set_muted(*muted, Some(spirc))?,
+                None => return Err(RpcError::invalid_params("muting is not supported")),
+            },
+            ("shuffle", Value::Bool(shuffle)) => spirc.shuffle(*shuffle)?,
+            ("loopStatus", Value::String(status)) => match status.as_str() {
+                "none" => {
+                    spirc.repeat_track(false)?;
+                    spirc.repeat(false)?;
+                }
+                "track" => spirc.repeat_track(true)?,
+                "playlist" => {
+                    spirc.repeat_track(false)?;
+                    spirc.repeat(true)?;
+                }
+                status => {
+                    return Err(RpcError::invalid_params(format!(
+                        "unknown loop status '{status}'"
+                    )));
+                }
+            },
+            (name, _) => {
+                return Err(RpcError::invalid_params(format!(
+                    "unsupported property '{name}'"
+                )));
+            }
+        }
+    }
+    Ok(json!("ok"))
+}
+
+/// Handles a single request and returns the response, unless the request was a notification.
+fn handle_request(line: &str, state: &Mutex<State>) -> Option<String> {
+    let request: Value = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(err) => {
+            let error = json!({"code": -32700, "message": err.to_string()});
+            return Some(json!({"jsonrpc": "2.0", "id": null, "error": error}).to_string());
+        }
+    };
+    let id = request.get("id")?.clone();
+    let method = request["method"].as_str().unwrap_or_default();
+    debug!("Snapcast request '{method}'");
+
+    let state = state.lock().expect("snapcast state has been poisoned");
+    let result = match method {
+        "Plugin.Stream.Player.Control" => control(&state, &request["params"]),
+        "Plugin.Stream.Player.SetProperty" => set_property(&state, &request["params"]),
+        "Plugin.Stream.Player.GetProperties" => Ok(state.properties()),
+        _ => Err(RpcError {
+            code: -32601,
+            message: "Method not found".to_string(),
+        }),
+    };
+    let response = match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err(RpcError { code, message }) => {
+            json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+        }
+    };
+    Some(response.to_string())
+}
+
+fn properties_notification(state: &State) -> String {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "Plugin.Stream.Player.Properties",
+        "params": state.properties(),
+    })
+    .to_string()
+}
+
+async fn handle_client(
+    stream: UnixStream,
+    state: Arc<Mutex<State>>,
+    mut notifications: broadcast::Receiver<String>,
+) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let ready = json!({"jsonrpc": "2.0", "method": "Plugin.Stream.Ready"}).to_string();
+    let properties =
+        properties_notification(&state.lock().expect("snapcast state has been poisoned"));
+    writer
+        .write_all(format!("{ready}\n{properties}\n").as_bytes())
+        .await?;
+
+    loop {
+        let message = tokio::select! {
+            line = lines.next_line() => match line? {
+                Some(line) => match handle_request(&line, &state) {
+                    Some(response) => response,
+                    None => continue,
+                },
+                None => return Ok(()),
+            },
+            notification = notifications.recv() => match notification {
+                Ok(notification) => notification,
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    properties_notification(&state.lock().expect("snapcast state has been poisoned"))
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+        };
+        writer.write_all(message.as_bytes()).await?;
+        writer.write_all(b"\n").await?;
+    }
+}
+
+/// Publishes the player state to connected Snapcast control scripts and executes their commands.
+pub(crate) struct SnapcastServer {
+    path: PathBuf,
+    state: Arc<Mutex<State>>,
+    notifications: broadcast::Sender<String>,
+    task: JoinHandle<()>,
+}
+
+impl SnapcastServer {
+    pub(crate) fn bind(path: &Path, mute: Arc<MuteMixer>) -> io::Result<Self> {
+        // a socket left over from a previous run would make binding fail
+        if fs::metadata(path).is_ok_and(|metadata| {
+            use std::os::unix::fs::FileTypeExt as _;
+            metadata.file_type().is_socket()
+        }) {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        info!("Listening for Snapcast control scripts on {path:?}");
+
+        let state = Arc::new(Mutex::new(State {
+            mute: Some(mute),
+            ..State::default()
+        }));
+        let (notifications, _) = broadcast::channel(16);
+
+        let task = {
+            let state = state.clone();
+            let notifications = notifications.clone();
+            tokio::spawn(async move {
+                loop {
+                    let stream = match listener.accept().await {
+                        Ok((stream, _)) => stream,
+                        Err(err) => {
+                            warn!("Failed to accept Snapcast connection: {err}");
+                            continue;
+                        }
+                    };
+                    debug!("Snapcast control script connected");
+                    let client = handle_client(stream, state.clone(), notifications.subscribe());
+                    tokio::spawn(async move {
+                        if let Err(err) = client.await {
+                            debug!("Snapcast control script disconnected: {err}");
+                        }
+                    });
+                }
+            })
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            state,
+            notifications,
+            task,
+        })
+    }
+
+    pub(crate) fn set_spirc(&self, spirc: Option<Arc<Spirc>>) {
+        let mut state = self.state.lock().expect("snapcast state has been poisoned");
+        if spirc.is_none() {
+            *state = State {
+                mute: state.mute.take(),
+                ..State::default()
+            };
+        } else {
+            state.spirc = spirc;
+        }
+        let _ = self.notifications.send(properties_notification(&state));
+    }
+
+    pub(crate) fn handle_event(&self, event: &PlayerEvent) {
+        let mut state = self.state.lock().expect("snapcast state has been poisoned");
+        if state.handle_event(event) {
+            let _ = self.notifications.send(properties_notification(&state));
+        }
+    }
+}
+
+impl Drop for SnapcastServer {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Connects stdin and stdout to the control socket of a running `spotifyd`.
+pub(crate) fn run_control_bridge(socket: &Path) -> eyre::Result<()> {
+    let stream = std::os::unix::net::UnixStream::connect(socket)
+        .wrap_err_with(|| format!("failed to connect to {socket:?}"))?;
+
+    let mut writer = stream.try_clone()?;
+    thread::spawn(move || {
+        let _ = io::copy(&mut io::stdin().lock(), &mut writer);
+        // let spotifyd know that snapserver is gone
+        let _ = writer.shutdown(std::net::Shutdown::Write);
+    });
+    io::copy(&mut &stream, &mut io::stdout().lock()).wrap_err("connection to spotifyd failed")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapcast_requests() {
+        let state = Mutex::new(State::default());
+
+        let response = handle_request(
+            r#"{"id": 1, "jsonrpc": "2.0", "method": "Plugin.Stream.Player.GetProperties"}"#,
+            &state,
+        )
+        .unwrap();
+        let response: Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["result"]["playbackStatus"], "stopped");
+        assert_eq!(response["result"]["canControl"], false);
+
+        let response = handle_request(
+            r#"{"id": 2, "jsonrpc": "2.0", "method": "Plugin.Stream.Player.Control", "params": {"command": "play"}}"#,
+            &state,
+        )
+        .unwrap();
+        assert!(response.contains("not connected"), "{response}");
+
+        let response = handle_request(r#"{"id": 3, "jsonrpc": "2.0", "method": "Foo"}"#, &state);
+        assert!(response.unwrap().contains("-32601"));
+
+        assert!(
+            handle_request(r#"{"jsonrpc": "2.0", "method": "Foo"}"#, &state).is_none(),
+            "notifications must not be answered"
+        );
+    }
+}
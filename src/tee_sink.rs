@@ -0,0 +1,297 @@
+//! A sink that plays the same audio on several outputs at once.
+//!
+//! Every output runs on a thread of its own, so that one output can be dropped if it fails or
+//! doesn't keep up, without stalling the others. A dropped output is only reopened once its
+//! thread has exited, since that thread may still hold the device.
+
+use crate::main_loop::AudioOutput;
+use librespot_playback::{
+    audio_backend::{Sink, SinkError, SinkResult},
+    convert::Converter,
+    decoder::AudioPacket,
+    dither::DithererBuilder,
+};
+use log::{debug, error, info, warn};
+use std::{
+    sync::{
+        Arc,
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// The number of packets that may be queued for an output.
+const OUTPUT_QUEUE: usize = 4;
+/// How long an output may block the playback, before it is dropped.
+const STALL_TIMEOUT: Duration = Duration::from_secs(1);
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+enum Message {
+    Start,
+    Stop,
+    Samples(Arc<[f64]>),
+    Raw(Arc<[u8]>),
+}
+
+/// The thread of an output.
+struct Branch {
+    /// The queue of the output, `None` if the output has been dropped.
+    sender: Option<SyncSender<Message>>,
+    thread: JoinHandle<()>,
+}
+
+impl Branch {
+    fn spawn(output: AudioOutput, ditherer: Option<DithererBuilder>) -> Self {
+        let (tx, rx) = mpsc::sync_channel(OUTPUT_QUEUE);
+        let thread = thread::spawn(move || {
+            if let Err(err) = run_output(&output, ditherer, rx) {
+                error!("Dropping the output {}: {err}", output.name);
+            }
+        });
+        Self {
+            sender: Some(tx),
+            thread,
+        }
+    }
+}
+
+struct TeeSink {
+    outputs: Vec<AudioOutput>,
+    branches: Vec<Branch>,
+    ditherer: Option<DithererBuilder>,
+}
+
+/// Opens the outputs, which convert the audio with the ditherer of the player.
+pub(crate) fn open(outputs: Vec<AudioOutput>, ditherer: Option<DithererBuilder>) -> Box<dyn Sink> {
+    let branches = outputs
+        .iter()
+        .map(|output| Branch::spawn(output.clone(), ditherer))
+        .collect();
+    Box::new(TeeSink {
+        outputs,
+        branches,
+        ditherer,
+    })
+}
+
+fn run_output(
+    output: &AudioOutput,
+    ditherer: Option<DithererBuilder>,
+    messages: Receiver<Message>,
+) -> SinkResult<()> {
+    // sinks are not Send, so they have to be created on this thread
+    let mut sink = output.open();
+    let mut converter = Converter::new(ditherer);
+    for message in messages {
+        match message {
+            Message::Start => sink.start()?,
+            Message::Stop => sink.stop()?,
+            Message::Samples(samples) => {
+                sink.write(AudioPacket::Samples(samples.to_vec()), &mut converter)?
+            }
+            Message::Raw(bytes) => sink.write(AudioPacket::Raw(bytes.to_vec()), &mut converter)?,
+        }
+    }
+    Ok(())
+}
+
+impl TeeSink {
+    /// Sends a message to every output and waits for the slow ones, up to the stall timeout.
+    fn send(&mut self, message: impl Fn() -> Message) -> SinkResult<()> {
+        let deadline = Instant::now() + STALL_TIMEOUT;
+        for (output, branch) in self.outputs.iter().zip(&mut self.branches) {
+            let Some(sender) = &branch.sender else {
+                continue;
+            };
+            let mut pending = message();
+            loop {
+                match sender.try_send(pending) {
+                    Ok(()) => break,
+                    Err(TrySendError::Full(message)) if Instant::now() < deadline => {
+                        pending = message;
+                        thread::sleep(POLL_INTERVAL);
+                    }
+                    Err(TrySendError::Full(_)) => {
+                        warn!("Dropping the output {}, which doesn't keep up", output.name);
+                        // the thread exits once the output returns
+                        branch.sender = None;
+                        break;
+                    }
+                    // the output has failed and logged why
+                    Err(TrySendError::Disconnected(_)) => {
+                        branch.sender = None;
+                        break;
+                    }
+                }
+            }
+        }
+        if self.branches.iter().all(|branch| branch.sender.is_none()) {
+            return Err(SinkError::OnWrite(
+                "<TeeSink> all outputs have been dropped".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Sink for TeeSink {
+    fn start(&mut self) -> SinkResult<()> {
+        // give dropped outputs another chance, once they have let go of the device
+        for (output, branch) in self.outputs.iter().zip(&mut self.branches) {
+            if branch.sender.is_some() {
+                continue;
+            }
+            if !branch.thread.is_finished() {
+                debug!(
+                    "Not reopening the output {} yet, it is still busy",
+                    output.name
+                );
+                continue;
+            }
+            info!("Reopening the output {}", output.name);
+            let old = std::mem::replace(branch, Branch::spawn(output.clone(), self.ditherer));
+            let _ = old.thread.join();
+        }
+        self.send(|| Message::Start)
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        // the player exits if stopping fails, and there is nothing to stop without outputs
+        if let Err(err) = self.send(|| Message::Stop) {
+            debug!("Not stopping the outputs: {err}");
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, packet: AudioPacket, _: &mut Converter) -> SinkResult<()> {
+        match packet {
+            AudioPacket::Samples(samples) => {
+                let samples: Arc<[f64]> = samples.into();
+                self.send(|| Message::Samples(samples.clone()))
+            }
+            AudioPacket::Raw(bytes) => {
+                let bytes: Arc<[u8]> = bytes.into();
+                self.send(|| Message::Raw(bytes.clone()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use librespot_playback::config::AudioFormat;
+    use std::sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    /// Records the written samples, or blocks forever if it is stuck.
+    struct TestSink {
+        written: Arc<Mutex<Vec<f64>>>,
+        stuck: bool,
+    }
+
+    impl Sink for TestSink {
+        fn write(&mut self, packet: AudioPacket, _: &mut Converter) -> SinkResult<()> {
+            if self.stuck {
+                loop {
+                    thread::park();
+                }
+            }
+            let AudioPacket::Samples(samples) = packet else {
+                return Err(SinkError::InvalidParams("raw".to_string()));
+            };
+            self.written.lock().unwrap().extend(samples);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_tee_drops_stuck_outputs() {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let output = |name: &str, written: &Arc<Mutex<Vec<f64>>>, stuck| {
+            let written = written.clone();
+            let opened = opened.clone();
+            AudioOutput {
+                name: name.to_string(),
+                builder: Arc::new(move |_, _| {
+                    opened.fetch_add(1, Ordering::SeqCst);
+                    Box::new(TestSink {
+                        written: written.clone(),
+                        stuck,
+                    })
+                }),
+                device: None,
+                format: AudioFormat::S16,
+            }
+        };
+        let healthy = Arc::new(Mutex::new(Vec::new()));
+        let stuck = Arc::new(Mutex::new(Vec::new()));
+        let mut tee = open(
+            vec![
+                output("healthy", &healthy, false),
+                output("stuck", &stuck, true),
+            ],
+            None,
+        );
+        let mut converter = Converter::new(None);
+
+        tee.start().unwrap();
+        let started = Instant::now();
+        for i in 0..2 * OUTPUT_QUEUE + 2 {
+            tee.write(AudioPacket::Samples(vec![i as f64]), &mut converter)
+                .unwrap();
+        }
+        assert!(started.elapsed() < 2 * STALL_TIMEOUT);
+        tee.stop().unwrap();
+        // the stuck output isn't reopened while its thread still holds it
+        tee.start().unwrap();
+        tee.stop().unwrap();
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+        drop(tee);
+
+        // wait for the healthy output to finish writing
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while healthy.lock().unwrap().len() < 2 * OUTPUT_QUEUE + 2 && Instant::now() < deadline {
+            thread::sleep(POLL_INTERVAL);
+        }
+        let expected: Vec<f64> = (0..2 * OUTPUT_QUEUE + 2).map(|i| i as f64).collect();
+        assert_eq!(*healthy.lock().unwrap(), expected);
+        assert!(stuck.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tee_stops_without_outputs() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let output = AudioOutput {
+            name: "failing".to_string(),
+            builder: Arc::new(move |_, _| {
+                Box::new(TestSink {
+                    written: written.clone(),
+                    stuck: false,
+                })
+            }),
+            device: None,
+            format: AudioFormat::S16,
+        };
+        let mut tee = open(vec![output], None);
+        let mut converter = Converter::new(None);
+
+        tee.start().unwrap();
+        // the output fails on raw packets
+        tee.write(AudioPacket::Raw(vec![0]), &mut converter)
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while tee
+            .write(AudioPacket::Samples(vec![0.0]), &mut converter)
+            .is_ok()
+        {
+            assert!(Instant::now() < deadline, "the output hasn't been dropped");
+            thread::sleep(POLL_INTERVAL);
+        }
+        // writing fails without outputs, but pausing doesn't
+        tee.stop().unwrap();
+    }
+}
@@ -0,0 +1,80 @@
+//! A cap on the volume, e.g. for amplifiers that are far too loud at full volume.
+//!
+//! The full range of the volume in Spotify is scaled down to the cap, instead of cutting it off,
+//! so that the volume can still be changed in fine steps.
+
+use librespot_playback::mixer::{Mixer, MixerConfig, VolumeGetter};
+use std::sync::Arc;
+
+/// Scales a volume in Spotify down to the volume of the mixer.
+pub(crate) fn limit(volume: u16, max_volume: u16) -> u16 {
+    ((volume as u32 * max_volume as u32 + u16::MAX as u32 / 2) / u16::MAX as u32) as u16
+}
+
+/// Scales a volume of the mixer up to the volume in Spotify.
+pub(crate) fn unlimit(volume: u16, max_volume: u16) -> u16 {
+    let max_volume = max_volume.max(1) as u32;
+    ((volume as u32 * u16::MAX as u32 + max_volume / 2) / max_volume).min(u16::MAX as u32) as u16
+}
+
+/// A mixer that scales the volume of another mixer down to the maximal volume.
+pub(crate) struct LimitedMixer {
+    mixer: Arc<dyn Mixer>,
+    max_volume: u16,
+}
+
+impl LimitedMixer {
+    pub(crate) fn new(mixer: Arc<dyn Mixer>, max_volume: u16) -> Self {
+        Self { mixer, max_volume }
+    }
+}
+
+impl Mixer for LimitedMixer {
+    fn open(_: MixerConfig) -> Result<Self, librespot_core::Error> {
+        Err(librespot_core::Error::unimplemented(
+            "a limited mixer wraps another mixer",
+        ))
+    }
+
+    fn volume(&self) -> u16 {
+        unlimit(self.mixer.volume(), self.max_volume)
+    }
+
+    fn set_volume(&self, volume: u16) {
+        self.mixer.set_volume(limit(volume, self.max_volume));
+    }
+
+    fn get_soft_volume(&self) -> Box<dyn VolumeGetter + Send> {
+        self.mixer.get_soft_volume()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use librespot_playback::{config::VolumeCtrl, mixer::softmixer::SoftMixer};
+
+    #[test]
+    fn test_limited_mixer() {
+        let soft_mixer = Arc::new(
+            SoftMixer::open(MixerConfig {
+                volume_ctrl: VolumeCtrl::Linear,
+                ..MixerConfig::default()
+            })
+            .unwrap(),
+        );
+        let max_volume = u16::MAX / 100 * 60;
+        let mixer = LimitedMixer::new(soft_mixer.clone(), max_volume);
+
+        mixer.set_volume(u16::MAX);
+        assert_eq!(soft_mixer.volume(), max_volume);
+        assert_eq!(mixer.volume(), u16::MAX);
+        let attenuation = mixer.get_soft_volume().attenuation_factor();
+        assert!((attenuation - 0.6).abs() < 1e-3, "{attenuation}");
+
+        // the soft mixer rounds down
+        mixer.set_volume(u16::MAX / 2);
+        assert!(mixer.volume().abs_diff(u16::MAX / 2) <= 1);
+        assert_eq!(unlimit(limit(1234, max_volume), max_volume), 1234);
+    }
+}
@@ -0,0 +1,306 @@
+//! Remembering the volume per Spotify user or client, to restore it when they take over.
+//!
+//! The volumes are stored in the cache directory. When a client takes over, Spotify first
+//! reports the user and client and then the volume that was left by the previous one, which is
+//! replaced with the remembered volume. Changes are saved once the volume has settled, so
+//! that dragging a volume slider doesn't write the file for every step.
+
+use crate::{config::RememberVolume, mute::MuteMixer};
+use librespot_connect::Spirc;
+use librespot_playback::{
+    mixer::Mixer,
+    player::{PlayerEvent, PlayerEventChannel},
+};
+use log::{debug, info, warn};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+use tokio::time::{Instant, sleep_until};
+
+const VOLUMES_FILE: &str = "volumes.json";
+/// How long the volume has to stay the same before it is saved.
+const SAVE_DELAY: Duration = Duration::from_secs(2);
+
+fn user_key(user: &str) -> String {
+    format!("user:{user}")
+}
+
+fn client_key(user: &str, client: &str) -> String {
+    format!("client:{user}:{client}")
+}
+
+pub(crate) struct VolumeMemory {
+    path: PathBuf,
+    remember: RememberVolume,
+    volumes: Mutex<BTreeMap<String, u16>>,
+}
+
+impl VolumeMemory {
+    pub(crate) fn load(cache_dir: &Path, remember: RememberVolume) -> Self {
+        let path = cache_dir.join(VOLUMES_FILE);
+        let volumes = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|err| {
+                warn!("Ignoring the remembered volumes in {path:?}: {err}");
+                BTreeMap::new()
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => {
+                warn!("Failed to read the remembered volumes from {path:?}: {err}");
+                BTreeMap::new()
+            }
+        };
+        Self {
+            path,
+            remember,
+            volumes: Mutex::new(volumes),
+        }
+    }
+
+    fn volumes(&self) -> MutexGuard<'_, BTreeMap<String, u16>> {
+        self.volumes
+            .lock()
+            .expect("volume memory has been poisoned")
+    }
+
+    /// The remembered volume of the first key that has one.
+    fn get(&self, keys: &[String]) -> Option<u16> {
+        let volumes = self.volumes();
+        keys.iter().find_map(|key| volumes.get(key).copied())
+    }
+
+    /// Remembers the volume, and returns whether it has changed and has to be saved.
+    fn remember(&self, keys: &[String], volume: u16) -> bool {
+        let mut volumes = self.volumes();
+        let mut changed = false;
+        for key in keys {
+            changed |= volumes.insert(key.clone(), volume) != Some(volume);
+        }
+        changed
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let data = serde_json::to_vec(&*self.volumes())?;
+        fs::write(&self.path, data)
+    }
+
+    /// Saves the volumes without blocking the runtime.
+    async fn save_in_background(self: &Arc<Self>) {
+        let memory = self.clone();
+        let result = tokio::task::spawn_blocking(move || memory.save())
+            .await
+            .unwrap_or_else(|err| Err(io::Error::other(err)));
+        if let Err(err) = result {
+            warn!("Failed to store the volume in {:?}: {err}", self.path);
+        }
+    }
+
+    /// The remembered volume to set, unless it is already set or would unmute.
+    fn restore(&self, keys: &[String], mute: &MuteMixer) -> Option<Action> {
+        let volume = self.get(keys)?;
+        // restoring would unmute
+        if mute.is_muted() || mute.volume() == volume {
+            return None;
+        }
+        info!(
+            "Restoring the remembered volume of {}%",
+            (volume as u32 * 100 + u16::MAX as u32 / 2) / u16::MAX as u32
+        );
+        Some(Action::Restore(volume))
+    }
+
+    /// Follows the player events, to remember the volume and restore it on takeovers.
+    pub(crate) async fn watch(
+        self: Arc<Self>,
+        mut events: PlayerEventChannel,
+        spirc: Arc<Spirc>,
+        mute: Arc<MuteMixer>,
+    ) {
+        let mut listener = Listener::default();
+        // when to save the changed volumes
+        let mut save_at = None;
+        loop {
+            tokio::select! {
+                event = events.recv() => {
+                    let Some(event) = event else {
+                        break;
+                    };
+                    match listener.handle(&self, event, &mute) {
+                        Some(Action::Restore(volume)) if spirc.set_volume(volume).is_ok() => {
+                            listener.restoring = Some(volume);
+                        }
+                        Some(Action::Save) => save_at = Some(Instant::now() + SAVE_DELAY),
+                        Some(Action::Restore(_)) | None => (),
+                    }
+                }
+                _ = sleep_until(save_at.unwrap_or_else(Instant::now)), if save_at.is_some() => {
+                    save_at = None;
+                    self.save_in_background().await;
+                }
+            }
+        }
+        if save_at.is_some() {
+            self.save_in_background().await;
+        }
+    }
+}
+
+/// What follows from a player event.
+#[derive(Debug, PartialEq)]
+enum Action {
+    /// Set the remembered volume.
+    Restore(u16),
+    /// Save the changed volumes.
+    Save,
+}
+
+/// The current user and client, as far as the player events tell.
+#[derive(Default)]
+struct Listener {
+    user: Option<String>,
+    keys: Vec<String>,
+    /// The restored volume, before which reported volumes are the previous ones.
+    restoring: Option<u16>,
+}
+
+impl Listener {
+    fn handle(
+        &mut self,
+        memory: &VolumeMemory,
+        event: PlayerEvent,
+        mute: &MuteMixer,
+    ) -> Option<Action> {
+        match event {
+            PlayerEvent::SessionConnected { user_name, .. } => {
+                self.keys = vec![user_key(&user_name)];
+                self.restoring = None;
+                self.user = Some(user_name);
+                match memory.remember {
+                    RememberVolume::User => memory.restore(&self.keys, mute),
+                    // the client follows
+                    RememberVolume::Client => None,
+                }
+            }
+            PlayerEvent::SessionClientChanged {
+                client_id,
+                client_name,
+                ..
+            } => {
+                let (Some(user), RememberVolume::Client) = (&self.user, memory.remember) else {
+                    return None;
+                };
+                let client = if client_name.is_empty() {
+                    client_id
+                } else {
+                    client_name
+                };
+                debug!("Remembering the volume of '{client}'");
+                // users get the volume of their other clients at first
+                self.keys = vec![client_key(user, &client), user_key(user)];
+                self.restoring = None;
+                memory.restore(&self.keys, mute)
+            }
+            PlayerEvent::VolumeChanged { volume } => {
+                if let Some(restored) = self.restoring {
+                    if volume == restored {
+                        self.restoring = None;
+                    }
+                    return None;
+                }
+                (!self.keys.is_empty() && !mute.is_muted() && memory.remember(&self.keys, volume))
+                    .then_some(Action::Save)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use librespot_playback::{
+        config::VolumeCtrl,
+        mixer::{MixerConfig, softmixer::SoftMixer},
+    };
+
+    fn client_changed(client_name: &str) -> PlayerEvent {
+        PlayerEvent::SessionClientChanged {
+            client_id: "id".to_string(),
+            client_name: client_name.to_string(),
+            client_brand_name: String::new(),
+            client_model_name: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_volume_memory() {
+        let cache_dir = tempfile::tempdir().unwrap();
+
+        let memory = VolumeMemory::load(cache_dir.path(), RememberVolume::Client);
+        let keys = [client_key("alice", "Pixel"), user_key("alice")];
+        assert!(memory.remember(&keys, 1000));
+        assert!(!memory.remember(&keys, 1000));
+        assert!(memory.remember(&[client_key("alice", "Laptop"), user_key("alice")], 2000));
+        memory.save().unwrap();
+
+        let memory = VolumeMemory::load(cache_dir.path(), RememberVolume::Client);
+        assert_eq!(memory.get(&keys), Some(1000));
+        // a new client of a known user
+        assert_eq!(
+            memory.get(&[client_key("alice", "Tablet"), user_key("alice")]),
+            Some(2000)
+        );
+        assert_eq!(memory.get(&[user_key("bob")]), None);
+    }
+
+    #[test]
+    fn test_restore() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let memory = VolumeMemory::load(cache_dir.path(), RememberVolume::Client);
+        let mixer = SoftMixer::open(MixerConfig {
+            volume_ctrl: VolumeCtrl::Linear,
+            ..MixerConfig::default()
+        })
+        .unwrap();
+        let mute = MuteMixer::new(Arc::new(mixer), None);
+        mute.set_volume(5000);
+        let connected = || PlayerEvent::SessionConnected {
+            connection_id: "connection".to_string(),
+            user_name: "alice".to_string(),
+        };
+
+        // the volume of the client is remembered
+        let mut listener = Listener::default();
+        assert_eq!(listener.handle(&memory, connected(), &mute), None);
+        assert_eq!(
+            listener.handle(&memory, client_changed("Pixel"), &mute),
+            None
+        );
+        assert_eq!(
+            listener.handle(&memory, PlayerEvent::VolumeChanged { volume: 1000 }, &mute),
+            Some(Action::Save)
+        );
+
+        // and restored when the client connects again
+        let mut listener = Listener::default();
+        assert_eq!(listener.handle(&memory, connected(), &mute), None);
+        assert_eq!(
+            listener.handle(&memory, client_changed("Pixel"), &mute),
+            Some(Action::Restore(1000))
+        );
+        listener.restoring = Some(1000);
+        // the volume left by the previous client isn't remembered
+        assert_eq!(
+            listener.handle(&memory, PlayerEvent::VolumeChanged { volume: 5000 }, &mute),
+            None
+        );
+        assert_eq!(
+            listener.handle(&memory, PlayerEvent::VolumeChanged { volume: 1000 }, &mute),
+            None
+        );
+        assert_eq!(memory.get(&[client_key("alice", "Pixel")]), Some(1000));
+    }
+}